@@ -33,6 +33,22 @@ fn main() {
         .compile("fastme");
     println!("cargo:rustc-link-search=native=third_party/fastme/");
     println!("cargo:rustc-link-lib=fastme");
+    cc::Build::new()
+        .cpp(true)
+        .warnings(false)
+        .file("rapidnj_wrapper.cpp")
+        .file("third_party/rapidNJ/ProgressBar.cpp")
+        .file("third_party/rapidNJ/diskMatrix.cpp")
+        .file("third_party/rapidNJ/distMatrixReader.cpp")
+        .file("third_party/rapidNJ/polytree.cpp")
+        .file("third_party/rapidNJ/rapidNJ.cpp")
+        .file("third_party/rapidNJ/rapidNJDisk.cpp")
+        .file("third_party/rapidNJ/rdDataInitialiser.cpp")
+        .compile("rapidnj");
+    println!("cargo:rerun-if-changed=third_party/fastme");
+    println!("cargo:rerun-if-changed=third_party/rapidNJ");
+    println!("cargo:rerun-if-changed=rapidnj_wrapper.h");
+    println!("cargo:rerun-if-changed=rapidnj_wrapper.cpp");
     // Tell cargo to invalidate the built crate whenever the wrapper changes
     println!("cargo:rerun-if-changed=wrapper.h");
     // The bindgen::Builder is the main entry point
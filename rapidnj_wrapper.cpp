@@ -0,0 +1,81 @@
+#include "rapidnj_wrapper.h"
+#include "third_party/rapidNJ/rapidNJ.h"
+#include "third_party/rapidNJ/rapidNJDisk.h"
+#include <sstream>
+
+/* the memory rapidNJ may use in bytes, as in rapidNJ's own `getMemSize` */
+static double memory_size(long memory_mb) {
+  if (memory_mb > 0) {
+    return memory_mb * 1024.0 * 1024.0;
+  }
+  double pages = (double) sysconf(_SC_PHYS_PAGES);
+  double page_size = (double) sysconf(_SC_PAGE_SIZE);
+  return pages * page_size * 0.8;
+}
+
+static polytree *run_in_memory(rapidnj_row_fn row_fn, void *data, int n, vector<string> *names,
+                               ProgressBar *pb) {
+  distType **matrix = new distType*[n];
+  double *row = new double[n];
+  for (int i = 0; i < n; i++) {
+    row_fn(data, i, row);
+    matrix[i] = new distType[n];
+    for (int j = 0; j < n; j++) {
+      matrix[i][j] = (distType) row[j];
+    }
+  }
+  delete[] row;
+  /* freed along with the matrix by rapidNJ */
+  distMatrixReader *reader = new distMatrixReader(false, n, false, names, matrix);
+  reader->initializeData();
+  rapidNJ nj(reader, n, false, pb);
+  return nj.run();
+}
+
+static polytree *run_on_disk(rapidnj_row_fn row_fn, void *data, int n, vector<string> *names,
+                             const char *cache_dir, long memory_mb, ProgressBar *pb) {
+  /* freed by rapidNJDisk */
+  diskMatrix *disk = new diskMatrix(cache_dir, n);
+  double *row = new double[n];
+  distType *disk_row = new distType[n];
+  for (int i = 0; i < n; i++) {
+    row_fn(data, i, row);
+    for (int j = 0; j < n; j++) {
+      disk_row[j] = (distType) row[j];
+    }
+    disk->writeArray(disk_row, i, n);
+  }
+  delete[] row;
+  delete[] disk_row;
+  /* the number of columns of the sorted matrix kept in memory */
+  double columns = memory_size(memory_mb) / ((double) n * (sizeof(cluster_pair) + sizeof(distType)));
+  int sorted_size = (int) min(columns, (double) n);
+  sorted_size = max(sorted_size, min(5, n));
+  rdDataInitialiser reader(false, sorted_size, cache_dir, n);
+  reader.initializeFromExistingMatrix(names, disk);
+  rapidNJDisk nj(&reader, false, false, pb);
+  return nj.run();
+}
+
+char *rapidnj_tree(rapidnj_row_fn row, void *data, int n, const char *cache_dir, long memory_mb) {
+  vector<string> names;
+  for (int i = 0; i < n; i++) {
+    names.push_back(to_string(i));
+  }
+  /* rapidNJ reports its progress on stderr */
+  streambuf *err = cerr.rdbuf(NULL);
+  ProgressBar pb;
+  polytree *tree = cache_dir == NULL
+    ? run_in_memory(row, data, n, &names, &pb)
+    : run_on_disk(row, data, n, &names, cache_dir, memory_mb, &pb);
+  cerr.rdbuf(err);
+  cerr.clear();
+  ostringstream out;
+  tree->serialize_tree(out);
+  delete tree;
+  return strdup(out.str().c_str());
+}
+
+void rapidnj_free(char *newick) {
+  free(newick);
+}
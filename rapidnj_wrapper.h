@@ -0,0 +1,24 @@
+#ifndef RAPIDNJ_WRAPPER_H
+#define RAPIDNJ_WRAPPER_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Fills `row` with the `n` distances from taxon `i` to every taxon */
+typedef void (*rapidnj_row_fn)(void *data, int i, double *row);
+
+/* Neighbor joining by rapidNJ on the symmetric `n` x `n` distance matrix whose
+ * rows are given one at a time by `row`, the leaves labeled by their index.
+ * With a `cache_dir`, the matrix is kept on disk there and rapidNJ uses at
+ * most `memory_mb` megabytes of memory (80% of the physical memory if 0).
+ * Returns the tree in Newick, to be freed by `rapidnj_free`. */
+char *rapidnj_tree(rapidnj_row_fn row, void *data, int n, const char *cache_dir, long memory_mb);
+
+void rapidnj_free(char *newick);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,247 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// How the distances of a pair of taxa across gene trees are summarized
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregation {
+    /// the weighted mean
+    Mean,
+    /// the weighted median
+    Median,
+    /// the weighted mean after dropping this fraction of the weight at both ends
+    Trimmed(f64),
+}
+
+impl fmt::Display for Aggregation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Aggregation::Mean => write!(f, "mean"),
+            Aggregation::Median => write!(f, "median"),
+            Aggregation::Trimmed(p) => write!(f, "trimmed:{}", p),
+        }
+    }
+}
+
+impl FromStr for Aggregation {
+    type Err = String;
+
+    /// `mean`, `median` or `trimmed[:FRACTION]`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let name = parts.next().unwrap_or_default().to_lowercase();
+        let params = parts
+            .map(|it| {
+                it.parse::<f64>()
+                    .map_err(|_| format!("invalid parameter `{}`", it))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        match (name.as_str(), &params[..]) {
+            ("mean", []) => Ok(Aggregation::Mean),
+            ("median", []) => Ok(Aggregation::Median),
+            ("trimmed", []) => Ok(Aggregation::Trimmed(0.1)),
+            ("trimmed", &[p]) if (0.0..0.5).contains(&p) => Ok(Aggregation::Trimmed(p)),
+            _ => Err(format!(
+                "expected mean, median or trimmed[:FRACTION] with FRACTION in [0, 0.5), found `{}`",
+                s
+            )),
+        }
+    }
+}
+
+/// The (distance, weight) samples of each pair of taxa, needed by the
+/// aggregations other than the mean
+#[derive(Debug, Clone)]
+pub struct PairSamples {
+    /// samples of the pair `(l, r)`, `l < r`, at `r * (r - 1) / 2 + l`
+    samples: Vec<Vec<(f64, f64)>>,
+    /// keep at most this many samples per pair, merging neighboring values
+    /// beyond that, or all of them if `None`
+    capacity: Option<usize>,
+}
+
+impl PairSamples {
+    pub fn new(n: usize, capacity: Option<usize>) -> Self {
+        let mut samples = PairSamples {
+            samples: Vec::new(),
+            capacity,
+        };
+        samples.grow(n);
+        samples
+    }
+
+    /// Makes room for the pairs of `n` taxa
+    pub fn grow(&mut self, n: usize) {
+        let len = n * n.saturating_sub(1) / 2;
+        if len > self.samples.len() {
+            self.samples.resize(len, Vec::new());
+        }
+    }
+
+    fn index(l: usize, r: usize) -> usize {
+        r * (r - 1) / 2 + l
+    }
+
+    /// The samples of the pair `(l, r)`, `l < r`
+    pub fn get(&self, l: usize, r: usize) -> &[(f64, f64)] {
+        &self.samples[Self::index(l, r)]
+    }
+
+    pub fn push(&mut self, l: usize, r: usize, dist: f64, weight: f64) {
+        let samples = &mut self.samples[Self::index(l, r)];
+        samples.push((dist, weight));
+        if let Some(capacity) = self.capacity {
+            if samples.len() >= 2 * capacity {
+                compact(samples, capacity);
+            }
+        }
+    }
+
+    /// Adds the samples of `rhs`, whose pairs must be a subset of ours
+    pub fn merge(&mut self, rhs: &PairSamples) {
+        for (samples, other) in self.samples.iter_mut().zip(&rhs.samples) {
+            samples.extend_from_slice(other);
+            if let Some(capacity) = self.capacity {
+                if samples.len() >= 2 * capacity {
+                    compact(samples, capacity);
+                }
+            }
+        }
+    }
+
+    /// The summary of the samples of the pair `(l, r)`, `None` without samples
+    pub fn summarize(&mut self, l: usize, r: usize, aggregation: Aggregation) -> Option<f64> {
+        let samples = &mut self.samples[Self::index(l, r)];
+        let total: f64 = samples.iter().map(|it| it.1).sum();
+        if total <= 0.0 {
+            return None;
+        }
+        samples.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+        match aggregation {
+            Aggregation::Mean => Some(samples.iter().map(|(d, w)| d * w).sum::<f64>() / total),
+            Aggregation::Median => {
+                let half = total / 2.0;
+                let mut cum = 0.0;
+                for (i, &(d, w)) in samples.iter().enumerate() {
+                    cum += w;
+                    if cum >= half {
+                        // an even split between two values, e.g. an even number of samples
+                        return Some(match samples.get(i + 1) {
+                            Some(&(next, _)) if (cum - half).abs() <= 1e-12 * total => {
+                                (d + next) / 2.0
+                            }
+                            _ => d,
+                        });
+                    }
+                }
+                samples.last().map(|it| it.0)
+            }
+            Aggregation::Trimmed(p) => {
+                let (lo, hi) = (p * total, (1.0 - p) * total);
+                let (mut sum, mut kept, mut cum) = (0.0, 0.0, 0.0);
+                for &(d, w) in samples.iter() {
+                    // the part of the weight of this sample within [lo, hi]
+                    let overlap = ((cum + w).min(hi) - cum.max(lo)).max(0.0);
+                    sum += d * overlap;
+                    kept += overlap;
+                    cum += w;
+                }
+                Some(sum / kept)
+            }
+        }
+    }
+}
+
+/// Halves the samples until at most `capacity` are left by merging each two
+/// consecutive values into their weighted mean, which keeps the mean exact and
+/// the quantiles approximate
+fn compact(samples: &mut Vec<(f64, f64)>, capacity: usize) {
+    while samples.len() > capacity.max(1) {
+        samples.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+        *samples = samples
+            .chunks(2)
+            .map(|it| match *it {
+                [(a, wa), (b, wb)] if wa + wb > 0.0 => ((a * wa + b * wb) / (wa + wb), wa + wb),
+                [(a, wa), (_, wb)] => (a, wa + wb),
+                _ => it[0],
+            })
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The summary of `samples`, all of the same pair
+    fn summary(samples: &[(f64, f64)], aggregation: Aggregation) -> Option<f64> {
+        let mut pairs = PairSamples::new(2, None);
+        for &(d, w) in samples {
+            pairs.push(0, 1, d, w);
+        }
+        pairs.summarize(0, 1, aggregation)
+    }
+
+    #[test]
+    fn weighted_median() {
+        let median = |samples: &[(f64, f64)]| summary(samples, Aggregation::Median).unwrap();
+        // ties at the middle
+        assert_eq!(
+            median(&[(3.0, 1.0), (2.0, 1.0), (1.0, 1.0), (2.0, 1.0)]),
+            2.0
+        );
+        // the weight decides, not the number of samples
+        assert_eq!(median(&[(5.0, 1.0), (1.0, 3.0)]), 1.0);
+        // an even split of the weight
+        assert_eq!(median(&[(1.0, 1.0), (3.0, 1.0)]), 2.0);
+        assert_eq!(median(&[(10.0, 1.0), (1.0, 2.0), (4.0, 1.0)]), 2.5);
+    }
+
+    #[test]
+    fn trimmed_mean() {
+        let trimmed =
+            |samples: &[(f64, f64)], p| summary(samples, Aggregation::Trimmed(p)).unwrap();
+        // the ties are kept whole, the outliers dropped
+        let ties = [(2.0, 1.0), (10.0, 1.0), (2.0, 1.0), (1.0, 1.0), (2.0, 1.0)];
+        assert_eq!(trimmed(&ties, 0.2), 2.0);
+        // the samples at the ends are cut through their weight
+        assert_eq!(trimmed(&[(0.0, 1.0), (1.0, 2.0), (4.0, 1.0)], 0.25), 1.0);
+        assert_eq!(trimmed(&[(0.0, 1.0), (10.0, 1.0)], 0.1), 5.0);
+        assert_eq!(trimmed(&ties, 0.0), 17.0 / 5.0);
+    }
+
+    #[test]
+    fn single_sample() {
+        for aggregation in [
+            Aggregation::Mean,
+            Aggregation::Median,
+            Aggregation::Trimmed(0.1),
+            Aggregation::Trimmed(0.4),
+        ] {
+            assert_eq!(summary(&[(5.0, 0.5)], aggregation), Some(5.0));
+            assert_eq!(summary(&[(5.0, 0.0)], aggregation), None);
+        }
+    }
+
+    #[test]
+    fn compact_at_sketch_size() {
+        let mut pairs = PairSamples::new(2, Some(4));
+        for d in 1..8 {
+            pairs.push(0, 1, d as f64, 1.0);
+        }
+        assert_eq!(pairs.get(0, 1).len(), 7);
+        // twice the sketch size halves the samples, keeping the mean exact
+        pairs.push(0, 1, 8.0, 1.0);
+        assert_eq!(
+            pairs.get(0, 1),
+            [(1.5, 2.0), (3.5, 2.0), (5.5, 2.0), (7.5, 2.0)]
+        );
+        assert_eq!(pairs.summarize(0, 1, Aggregation::Mean), Some(4.5));
+        assert_eq!(pairs.summarize(0, 1, Aggregation::Median), Some(4.5));
+        // merging compacts as well
+        let mut merged = PairSamples::new(2, Some(4));
+        merged.merge(&pairs);
+        merged.merge(&pairs);
+        assert_eq!(merged.get(0, 1).len(), 4);
+        assert_eq!(merged.summarize(0, 1, Aggregation::Mean), Some(4.5));
+    }
+}
@@ -0,0 +1,384 @@
+//! Neighbor joining in Rust, without going through FastME. All take the
+//! distances from the upper triangle of the matrix, and return an unrooted tree
+//! (a root of degree three) with branch lengths, its leaves being the taxon ids.
+use crate::tree::{Tree, TreeBuilder};
+use ndarray::{Array, Array2, Ix2};
+use tracing::warn;
+
+/// Neighbor joining (Saitou & Nei 1987)
+pub fn neighbor_joining(distance: &Array<f64, Ix2>) -> Tree {
+    let mask = Array2::ones(distance.raw_dim());
+    agglomerate(distance, &mask, false)
+}
+
+/// BioNJ (Gascuel 1997), neighbor joining with the reduction weighted by the
+/// variances of the distances
+pub fn bionj(distance: &Array<f64, Ix2>) -> Tree {
+    let mask = Array2::ones(distance.raw_dim());
+    agglomerate(distance, &mask, true)
+}
+
+/// BioNJ* (Criscuolo & Gascuel 2008), building the tree straight from a
+/// distance matrix with missing entries: the pairs with `mask` 0 are skipped,
+/// and the sums of the NJ criterion, branch lengths and variances only run over
+/// the taxa with both distances known. On a complete matrix, this is BioNJ.
+pub fn bionj_star(distance: &Array<f64, Ix2>, mask: &Array<f64, Ix2>) -> Tree {
+    agglomerate(distance, mask, true)
+}
+
+fn agglomerate(distance: &Array<f64, Ix2>, mask: &Array<f64, Ix2>, variances: bool) -> Tree {
+    let n = distance.shape()[0];
+    let mut state = Agglomeration::new(distance, mask, variances);
+    let mut builder = TreeBuilder::new(n);
+    // the tree node of the subtree in each slot
+    let mut nodes: Vec<usize> = (0..n).collect();
+    let mut warned = false;
+    while state.active.len() > 3 {
+        let (i, j) = state.best_pair().unwrap_or_else(|| {
+            if !warned {
+                warn!("BioNJ*: no known distances left between the subtrees, joining them arbitrarily");
+                warned = true;
+            }
+            (state.active[0], state.active[1])
+        });
+        let (li, lj) = state.join(i, j);
+        // negative branch lengths are set to 0
+        nodes[i] = builder.join(&[(nodes[i], li.max(0.0)), (nodes[j], lj.max(0.0))]);
+    }
+    let lengths = state.final_lengths();
+    let children: Vec<(usize, f64)> = state
+        .active
+        .iter()
+        .zip(lengths)
+        .map(|(&a, l)| (nodes[a], l.max(0.0)))
+        .collect();
+    builder.finish(&children)
+}
+
+/// The matrices of the subtrees left to join, each in the slot of its first
+/// taxon. `w` is 1 for the known entries of `d` and `v` and 0 otherwise.
+struct Agglomeration {
+    d: Array2<f64>,
+    /// the variances of BioNJ, initially the distances
+    v: Array2<f64>,
+    w: Array2<f64>,
+    /// `sums[[a, b]]`, the sum of `d[[a, k]]` over the common neighbors `k`
+    /// of `a` and `b`, i.e. the active slots with both distances known
+    sums: Array2<f64>,
+    /// the same for `v`
+    var_sums: Array2<f64>,
+    /// the number of common neighbors
+    common: Array2<f64>,
+    active: Vec<usize>,
+    /// weigh the reduction by the variances as in BioNJ, or take the mean as in NJ
+    variances: bool,
+}
+
+impl Agglomeration {
+    fn new(distance: &Array<f64, Ix2>, mask: &Array<f64, Ix2>, variances: bool) -> Self {
+        let n = distance.shape()[0];
+        let mut d = Array2::zeros((n, n));
+        let mut w = Array2::zeros((n, n));
+        for l in 0..n {
+            for r in (l + 1)..n {
+                if mask[[l, r]] > 0.0 {
+                    d[[l, r]] = distance[[l, r]];
+                    d[[r, l]] = distance[[l, r]];
+                    w[[l, r]] = 1.0;
+                    w[[r, l]] = 1.0;
+                }
+            }
+        }
+        let mut state = Agglomeration {
+            v: d.clone(),
+            d,
+            w,
+            sums: Array2::zeros((n, n)),
+            var_sums: Array2::zeros((n, n)),
+            common: Array2::zeros((n, n)),
+            active: (0..n).collect(),
+            variances,
+        };
+        for k in 0..n {
+            state.add_neighbor(k, 1.0);
+        }
+        state
+    }
+
+    /// Adds (`sign` 1) or removes (`sign` -1) `k` as a common neighbor of the
+    /// active pairs
+    fn add_neighbor(&mut self, k: usize, sign: f64) {
+        let covered: Vec<usize> = self
+            .active
+            .iter()
+            .copied()
+            .filter(|&a| self.w[[a, k]] > 0.0)
+            .collect();
+        for &a in &covered {
+            for &b in &covered {
+                self.sums[[a, b]] += sign * self.d[[a, k]];
+                self.var_sums[[a, b]] += sign * self.v[[a, k]];
+                self.common[[a, b]] += sign;
+            }
+        }
+    }
+
+    /// The known pair minimizing the NJ criterion averaged over their common
+    /// neighbors; pairs without common neighbors come last, by distance
+    fn best_pair(&self) -> Option<(usize, usize)> {
+        let mut best: Option<((bool, f64), usize, usize)> = None;
+        for (x, &a) in self.active.iter().enumerate() {
+            for &b in &self.active[x + 1..] {
+                if self.w[[a, b]] <= 0.0 {
+                    continue;
+                }
+                let (dab, s) = (self.d[[a, b]], self.common[[a, b]]);
+                // with all distances known, `(r - 2)` times this is the NJ criterion
+                let key = if s > 0.0 {
+                    (
+                        false,
+                        dab - (2.0 * dab + self.sums[[a, b]] + self.sums[[b, a]]) / s,
+                    )
+                } else {
+                    (true, dab)
+                };
+                if best.is_none_or(|(k, _, _)| key < k) {
+                    best = Some((key, a, b));
+                }
+            }
+        }
+        best.map(|(_, a, b)| (a, b))
+    }
+
+    /// Joins the subtrees in slots `i` and `j` into slot `i`, returning their
+    /// branch lengths
+    fn join(&mut self, i: usize, j: usize) -> (f64, f64) {
+        let s = self.common[[i, j]];
+        let known = self.w[[i, j]] > 0.0;
+        let (dij, vij) = (self.d[[i, j]], self.v[[i, j]]);
+        let li = match (known, s > 0.0) {
+            (true, true) => dij / 2.0 + (self.sums[[i, j]] - self.sums[[j, i]]) / (2.0 * s),
+            (true, false) => dij / 2.0,
+            (false, _) => 0.0,
+        };
+        let lj = if known { dij - li } else { 0.0 };
+        let lambda = if self.variances && known && s > 0.0 && vij > 0.0 {
+            (0.5 + (self.var_sums[[j, i]] - self.var_sums[[i, j]]) / (2.0 * s * vij))
+                .clamp(0.0, 1.0)
+        } else {
+            0.5
+        };
+        self.add_neighbor(i, -1.0);
+        self.add_neighbor(j, -1.0);
+        self.active.retain(|&a| a != j);
+        for x in 0..self.active.len() {
+            let k = self.active[x];
+            if k == i {
+                continue;
+            }
+            let entry = match (self.w[[i, k]] > 0.0, self.w[[j, k]] > 0.0) {
+                (true, true) => Some((
+                    lambda * (self.d[[i, k]] - li) + (1.0 - lambda) * (self.d[[j, k]] - lj),
+                    lambda * self.v[[i, k]] + (1.0 - lambda) * self.v[[j, k]]
+                        - lambda * (1.0 - lambda) * vij,
+                )),
+                (true, false) => Some((self.d[[i, k]] - li, self.v[[i, k]])),
+                (false, true) => Some((self.d[[j, k]] - lj, self.v[[j, k]])),
+                (false, false) => None,
+            };
+            let (d, v, w) = entry.map_or((0.0, 0.0, 0.0), |(d, v)| (d, v, 1.0));
+            self.d[[i, k]] = d;
+            self.d[[k, i]] = d;
+            self.v[[i, k]] = v;
+            self.v[[k, i]] = v;
+            self.w[[i, k]] = w;
+            self.w[[k, i]] = w;
+        }
+        for x in 0..self.active.len() {
+            let k = self.active[x];
+            self.w[[j, k]] = 0.0;
+            self.w[[k, j]] = 0.0;
+        }
+        // the sums of the pairs of `i`, then `i` as a neighbor of the others
+        for x in 0..self.active.len() {
+            let b = self.active[x];
+            let (mut sums, mut var_sums, mut rev_sums, mut rev_var_sums, mut common) =
+                (0.0, 0.0, 0.0, 0.0, 0.0);
+            for &k in &self.active {
+                let both = self.w[[i, k]] * self.w[[b, k]];
+                sums += both * self.d[[i, k]];
+                var_sums += both * self.v[[i, k]];
+                rev_sums += both * self.d[[b, k]];
+                rev_var_sums += both * self.v[[b, k]];
+                common += both;
+            }
+            self.sums[[i, b]] = sums;
+            self.var_sums[[i, b]] = var_sums;
+            self.sums[[b, i]] = rev_sums;
+            self.var_sums[[b, i]] = rev_var_sums;
+            self.common[[i, b]] = common;
+            self.common[[b, i]] = common;
+        }
+        self.add_neighbor(i, 1.0);
+        (li, lj)
+    }
+
+    /// The branch lengths of the (at most three) subtrees left, joined at the root
+    fn final_lengths(&self) -> Vec<f64> {
+        let active = &self.active;
+        let known = |a: usize, b: usize| (self.w[[a, b]] > 0.0).then(|| self.d[[a, b]]);
+        match active[..] {
+            [a, b] => {
+                let half = known(a, b).unwrap_or(0.0) / 2.0;
+                vec![half, half]
+            }
+            [a, b, c] => match (known(a, b), known(a, c), known(b, c)) {
+                (Some(ab), Some(ac), Some(bc)) => vec![
+                    (ab + ac - bc) / 2.0,
+                    (ab + bc - ac) / 2.0,
+                    (ac + bc - ab) / 2.0,
+                ],
+                // half the mean known distance to the other two
+                _ => active
+                    .iter()
+                    .map(|&x| {
+                        let dists: Vec<f64> = active.iter().filter_map(|&y| known(x, y)).collect();
+                        if dists.is_empty() {
+                            0.0
+                        } else {
+                            dists.iter().sum::<f64>() / (2.0 * dists.len() as f64)
+                        }
+                    })
+                    .collect(),
+            },
+            _ => vec![0.0; active.len()],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tree::TaxonSet;
+    use ndarray::arr2;
+
+    fn taxon_set(n: usize) -> TaxonSet {
+        let mut taxon_set = TaxonSet::new();
+        for name in ["A", "B", "C", "D", "E", "F"].iter().take(n) {
+            taxon_set.request(name.to_string());
+        }
+        taxon_set
+    }
+
+    /// the distances between the leaves of `((A:1,B:2):1,(C:1,D:2):1,(E:1,F:2):1);`
+    fn three_cherries() -> Array2<f64> {
+        arr2(&[
+            [0.0, 3.0, 4.0, 5.0, 4.0, 5.0],
+            [0.0, 0.0, 5.0, 6.0, 5.0, 6.0],
+            [0.0, 0.0, 0.0, 3.0, 4.0, 5.0],
+            [0.0, 0.0, 0.0, 0.0, 5.0, 6.0],
+            [0.0, 0.0, 0.0, 0.0, 0.0, 3.0],
+            [0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+        ])
+    }
+
+    /// the length of the path between each pair of leaves of `tree`
+    fn path_lengths(tree: &Tree, n: usize) -> Array2<f64> {
+        let ancestors = |mut node: usize| {
+            let mut path = vec![(node, 0.0)];
+            let mut depth = 0.0;
+            while tree.parents[node] >= 0 {
+                depth += tree.lengths[node];
+                node = tree.parents[node] as usize;
+                path.push((node, depth));
+            }
+            path
+        };
+        let mut lengths = Array2::zeros((n, n));
+        for l in 0..n {
+            for r in (l + 1)..n {
+                let (left, right) = (ancestors(l), ancestors(r));
+                let (_, up) = left
+                    .iter()
+                    .find(|(a, _)| right.iter().any(|(b, _)| a == b))
+                    .unwrap();
+                let (_, down) = right
+                    .iter()
+                    .find(|(b, _)| left.iter().any(|(a, _)| a == b))
+                    .unwrap();
+                lengths[[l, r]] = up + down;
+            }
+        }
+        lengths
+    }
+
+    #[test]
+    fn additive_four_taxa() {
+        // the distances between the leaves of `((A:1,B:2):3,C:1,D:4);`
+        let distance = arr2(&[
+            [0.0, 3.0, 5.0, 8.0],
+            [0.0, 0.0, 6.0, 9.0],
+            [0.0, 0.0, 0.0, 5.0],
+            [0.0, 0.0, 0.0, 0.0],
+        ]);
+        let taxon_set = taxon_set(4);
+        for tree in [neighbor_joining(&distance), bionj(&distance)] {
+            assert_eq!(tree.newick(&taxon_set), "((A:1,B:2):3,C:1,D:4);");
+        }
+    }
+
+    /// a non-additive matrix, whose trees below were worked out with exact
+    /// fractions from the formulas of Gascuel (1997) and Criscuolo & Gascuel (2008)
+    fn non_additive() -> Array2<f64> {
+        arr2(&[
+            [0.0, 4.0, 3.5, 6.0, 3.0, 4.0],
+            [0.0, 0.0, 4.5, 7.0, 5.5, 6.5],
+            [0.0, 0.0, 0.0, 3.0, 3.0, 4.0],
+            [0.0, 0.0, 0.0, 0.0, 5.5, 6.5],
+            [0.0, 0.0, 0.0, 0.0, 0.0, 3.0],
+            [0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+        ])
+    }
+
+    #[test]
+    fn non_additive_matrix() {
+        let taxon_set = taxon_set(6);
+        // λ is 11/12, then 3/4 and 74/143 instead of NJ's 1/2
+        assert_eq!(
+            bionj(&non_additive()).newick(&taxon_set),
+            "(((A:1,B:3):0.6875,(C:0.25,D:2.75):1.3125):0.4375,E:1,F:2);"
+        );
+        assert_eq!(
+            neighbor_joining(&non_additive()).newick(&taxon_set),
+            "(((A:1,B:3):0.625,(C:0.25,D:2.75):1.125):0.625,E:1,F:2);"
+        );
+    }
+
+    #[test]
+    fn masked_pair_changes_lambda() {
+        let mut distance = non_additive();
+        distance[[0, 1]] = 1000.0;
+        let mut mask = Array2::ones((6, 6));
+        mask[[0, 1]] = 0.0;
+        let taxon_set = taxon_set(6);
+        // A and B cannot be joined first, and λ becomes 11/12, 47/215 and
+        // 33481/71810 as the sums of the variances skip the masked pair
+        assert_eq!(
+            bionj_star(&distance, &mask).newick(&taxon_set),
+            "((A:1.75,(B:3.5,(C:0.25,D:2.75):0.75):0.75):0.25,E:1,F:2);"
+        );
+    }
+
+    #[test]
+    fn masked_pairs_are_skipped() {
+        let mut distance = three_cherries();
+        let mut mask = Array2::ones((6, 6));
+        for (l, r) in [(0, 5), (1, 3)] {
+            distance[[l, r]] = 1000.0;
+            mask[[l, r]] = 0.0;
+        }
+        // the masked distances are recovered from the tree
+        let tree = bionj_star(&distance, &mask);
+        assert_eq!(path_lengths(&tree, 6), three_cherries());
+    }
+}
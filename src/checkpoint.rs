@@ -0,0 +1,364 @@
+//! Partial states written by `wastrid accumulate` and summed by `wastrid merge`,
+//! e.g. to split the gene trees of a large dataset across cluster jobs
+use crate::aggregate::Aggregation;
+use crate::internode::UstarState;
+use crate::tree::{open_input, InputError, Mode, TaxonSet, UstarConfig};
+use clap::ArgEnum;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::warn;
+
+const HEADER: &str = "#wastrid-state 1";
+/// the value of a setting that differs between the input files
+const MIXED: &str = "mixed";
+
+/// The settings and counts recorded along with the distances
+#[derive(Debug, Clone)]
+pub struct StateInfo {
+    /// `None` if the input files were read in different modes
+    pub mode: Option<Mode>,
+    /// `None` if the input files had different bounds
+    pub bounds: Option<(f64, f64)>,
+    pub aggregation: Aggregation,
+    pub sketch_size: Option<usize>,
+    /// number of gene trees accumulated
+    pub ngenes: usize,
+}
+
+impl StateInfo {
+    pub fn new(config: &UstarConfig, ngenes: usize) -> Self {
+        StateInfo {
+            mode: Some(config.mode),
+            bounds: Some((config.lower_bound, config.upper_bound)),
+            aggregation: config.aggregation,
+            sketch_size: config.sketch_size,
+            ngenes,
+        }
+    }
+
+    /// The settings of input files read with `files`, their per-file mode and
+    /// bounds overriding those of `config`
+    pub fn from_files(config: &UstarConfig, files: &[UstarConfig], ngenes: usize) -> Self {
+        let mut info = StateInfo::new(files.first().unwrap_or(config), ngenes);
+        for file in files {
+            info.mix(&StateInfo::new(file, 0));
+        }
+        info
+    }
+
+    /// Marks the mode and bounds as mixed where they differ from `other`'s
+    fn mix(&mut self, other: &StateInfo) {
+        if self.mode != other.mode {
+            self.mode = None;
+        }
+        if self.bounds != other.bounds {
+            self.bounds = None;
+        }
+    }
+
+    fn mode_name(&self) -> &str {
+        match self.mode {
+            Some(mode) => mode.to_possible_value().unwrap().get_name(),
+            None => MIXED,
+        }
+    }
+
+    fn bounds_text(&self) -> String {
+        match self.bounds {
+            Some((lower, upper)) => format!("{} {}", lower, upper),
+            None => MIXED.to_string(),
+        }
+    }
+}
+
+/// Writes the un-flattened state: a header with the settings and the taxon
+/// names, then one `l r sum weight [distance:weight ...]` line per covered pair
+pub fn write_state<W: Write>(
+    mut writer: W,
+    taxon_set: &TaxonSet,
+    state: &UstarState,
+    info: &StateInfo,
+) -> io::Result<()> {
+    writeln!(writer, "{}", HEADER)?;
+    writeln!(writer, "mode {}", info.mode_name())?;
+    writeln!(writer, "bounds {}", info.bounds_text())?;
+    writeln!(writer, "aggregation {}", info.aggregation)?;
+    if let Some(k) = info.sketch_size {
+        writeln!(writer, "sketch {}", k)?;
+    }
+    writeln!(writer, "genes {}", info.ngenes)?;
+    let n = taxon_set.len();
+    writeln!(writer, "taxa {}", n)?;
+    for name in &taxon_set.names {
+        writeln!(writer, "{}", name)?;
+    }
+    for r in 0..n {
+        for l in 0..=r {
+            if state.mask[[l, r]] <= 0.0 {
+                continue;
+            }
+            write!(
+                writer,
+                "{}\t{}\t{}\t{}",
+                l,
+                r,
+                state.dm[[l, r]],
+                state.mask[[l, r]]
+            )?;
+            if let (Some(samples), true) = (&state.samples, l < r) {
+                for (d, w) in samples.get(l, r) {
+                    write!(writer, "\t{}:{}", d, w)?;
+                }
+            }
+            writeln!(writer)?;
+        }
+    }
+    writer.flush()
+}
+
+/// Sums the partial states, aligning their taxa by name
+pub fn merge_states(paths: &[PathBuf]) -> Result<(TaxonSet, UstarState, StateInfo), InputError> {
+    let mut taxon_set = TaxonSet::new();
+    let mut merged: Option<(UstarState, StateInfo)> = None;
+    for path in paths {
+        read_state(path, &mut taxon_set, &mut merged)
+            .map_err(|source| InputError::io(path, source))?;
+    }
+    let (state, info) = merged.expect("at least one partial state");
+    Ok((taxon_set, state, info))
+}
+
+fn read_state(
+    path: &Path,
+    taxon_set: &mut TaxonSet,
+    merged: &mut Option<(UstarState, StateInfo)>,
+) -> io::Result<()> {
+    let mut lines = open_input(path)?.lines().enumerate();
+    let (_, header) = next_line(&mut lines)?;
+    if header.trim_end() != HEADER {
+        return Err(invalid_line(
+            0,
+            "not a partial state written by `wastrid accumulate`",
+        ));
+    }
+    let mut info = StateInfo::new(&UstarConfig::default(), 0);
+    let ntaxa = loop {
+        let (i, line) = next_line(&mut lines)?;
+        let (key, value) = line.split_once(' ').unwrap_or((&line, ""));
+        let value = value.trim();
+        match key {
+            "mode" if value == MIXED => info.mode = None,
+            "mode" => {
+                info.mode = Some(Mode::from_str(value, true).map_err(|it| invalid_line(i, &it))?)
+            }
+            "bounds" if value == MIXED => info.bounds = None,
+            "bounds" => {
+                info.bounds = value
+                    .split_once(' ')
+                    .and_then(|(a, b)| Some((a.parse().ok()?, b.parse().ok()?)))
+                    .map(Some)
+                    .ok_or_else(|| invalid_line(i, "expected `bounds LOWER UPPER`"))?
+            }
+            "aggregation" => {
+                info.aggregation = value.parse().map_err(|it: String| invalid_line(i, &it))?
+            }
+            "sketch" => info.sketch_size = Some(parse(i, value)?),
+            "genes" => info.ngenes = parse(i, value)?,
+            "taxa" => break parse::<usize>(i, value)?,
+            _ => return Err(invalid_line(i, &format!("unknown setting `{}`", key))),
+        }
+    };
+    let mut ids = Vec::with_capacity(ntaxa);
+    for _ in 0..ntaxa {
+        let (_, name) = next_line(&mut lines)?;
+        ids.push(taxon_set.request(name));
+    }
+    let state = match merged {
+        Some((state, merged_info)) => {
+            if info.aggregation != merged_info.aggregation {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "aggregation {} differs from {} of the previous partial states",
+                        info.aggregation, merged_info.aggregation
+                    ),
+                ));
+            }
+            if info.mode != merged_info.mode || info.bounds != merged_info.bounds {
+                warn!(
+                    "{} was accumulated with mode {} and bounds {}, unlike the previous partial states ({} and {})",
+                    path.display(),
+                    info.mode_name(),
+                    info.bounds_text(),
+                    merged_info.mode_name(),
+                    merged_info.bounds_text()
+                );
+                merged_info.mix(&info);
+            }
+            merged_info.ngenes += info.ngenes;
+            state.grow(taxon_set.len());
+            state
+        }
+        None => {
+            let config = UstarConfig {
+                aggregation: info.aggregation,
+                sketch_size: info.sketch_size,
+                ..UstarConfig::default()
+            };
+            let state = UstarState::from_taxon_set(taxon_set, &config);
+            &mut merged.insert((state, info)).0
+        }
+    };
+    for (i, line) in lines {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 4 {
+            return Err(invalid_line(
+                i,
+                "expected `l r sum weight [distance:weight ...]`",
+            ));
+        }
+        let (l, r): (usize, usize) = (parse(i, fields[0])?, parse(i, fields[1])?);
+        let (a, b) = match (ids.get(l), ids.get(r)) {
+            (Some(&a), Some(&b)) => (a.min(b), a.max(b)),
+            _ => return Err(invalid_line(i, "taxon index out of range")),
+        };
+        state.dm[[a, b]] += parse::<f64>(i, fields[2])?;
+        state.mask[[a, b]] += parse::<f64>(i, fields[3])?;
+        if let Some(samples) = &mut state.samples {
+            for sample in &fields[4..] {
+                let (d, w) = sample
+                    .split_once(':')
+                    .ok_or_else(|| invalid_line(i, "expected `distance:weight`"))?;
+                samples.push(a, b, parse(i, d)?, parse(i, w)?);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn next_line(
+    lines: &mut impl Iterator<Item = (usize, io::Result<String>)>,
+) -> io::Result<(usize, String)> {
+    match lines.next() {
+        Some((i, line)) => Ok((i, line?)),
+        None => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "truncated partial state",
+        )),
+    }
+}
+
+fn parse<T: FromStr>(i: usize, s: &str) -> io::Result<T> {
+    s.trim()
+        .parse()
+        .map_err(|_| invalid_line(i, &format!("invalid number `{}`", s)))
+}
+
+fn invalid_line(i: usize, message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("line {}: {}", i + 1, message),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tree::{parse_newick, Tree};
+    use std::fs::{self, File};
+
+    const GENES: [&str; 3] = [
+        "((A:1,B:1):1,(C:1,D:1):1);",
+        "((A:1,C:2):1,(B:1,E:1):1);",
+        "((E:1,D:1):3,(B:1,A:1):1);",
+    ];
+
+    fn accumulate(genes: &[&str], config: &UstarConfig) -> (TaxonSet, UstarState) {
+        let mut taxon_set = TaxonSet::new();
+        let trees: Vec<Tree> = genes
+            .iter()
+            .map(|it| parse_newick(&mut taxon_set, it, config).unwrap())
+            .collect();
+        let mut state = UstarState::from_taxon_set(&taxon_set, config);
+        state.add_trees(&trees, config);
+        (taxon_set, state)
+    }
+
+    #[test]
+    fn write_and_merge_partial_states() {
+        for aggregation in [Aggregation::Mean, Aggregation::Median] {
+            let config = UstarConfig {
+                aggregation,
+                ..UstarConfig::default()
+            };
+            // the taxa of the two parts come in different orders
+            let paths: Vec<PathBuf> = [&GENES[..2], &GENES[2..]]
+                .iter()
+                .enumerate()
+                .map(|(i, genes)| {
+                    let (taxon_set, state) = accumulate(genes, &config);
+                    let path = std::env::temp_dir().join(format!(
+                        "wastrid-state-{}-{}-{}",
+                        std::process::id(),
+                        aggregation,
+                        i
+                    ));
+                    let info = StateInfo::new(&config, genes.len());
+                    write_state(File::create(&path).unwrap(), &taxon_set, &state, &info).unwrap();
+                    path
+                })
+                .collect();
+            let merged = merge_states(&paths);
+            for path in &paths {
+                fs::remove_file(path).unwrap();
+            }
+            let (taxon_set, mut state, info) = merged.unwrap();
+            assert_eq!(info.ngenes, 3);
+            assert_eq!(info.aggregation, aggregation);
+
+            let (whole_set, mut whole) = accumulate(&GENES, &config);
+            state.flatten();
+            whole.flatten();
+            let id = |name: &str| whole_set.names.iter().position(|it| it == name).unwrap();
+            for l in 0..taxon_set.len() {
+                for r in (l + 1)..taxon_set.len() {
+                    let (a, b) = (id(&taxon_set.names[l]), id(&taxon_set.names[r]));
+                    let (a, b) = (a.min(b), a.max(b));
+                    assert_eq!(state.mask[[l, r]], whole.mask[[a, b]]);
+                    assert!((state.dm[[l, r]] - whole.dm[[a, b]]).abs() < 1e-12);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mixed_file_settings() {
+        let config = UstarConfig::default();
+        let bootstrap = UstarConfig {
+            upper_bound: 100.0,
+            ..UstarConfig::default()
+        };
+        let info = StateInfo::from_files(&config, &[config.clone(), config.clone()], 2);
+        assert_eq!(
+            (info.mode, info.bounds),
+            (Some(config.mode), Some((0.0, 1.0)))
+        );
+        let info = StateInfo::from_files(&config, &[config.clone(), bootstrap], 2);
+        assert_eq!((info.mode, info.bounds), (Some(config.mode), None));
+
+        let (taxon_set, state) = accumulate(&GENES, &config);
+        let path = std::env::temp_dir().join(format!("wastrid-state-{}-mixed", std::process::id()));
+        write_state(File::create(&path).unwrap(), &taxon_set, &state, &info).unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        let merged = merge_states(std::slice::from_ref(&path));
+        fs::remove_file(&path).unwrap();
+        assert!(text.contains("\nbounds mixed\n"));
+        let (_, _, merged) = merged.unwrap();
+        assert_eq!((merged.mode, merged.bounds), (Some(config.mode), None));
+    }
+}
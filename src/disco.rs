@@ -0,0 +1,447 @@
+//! DISCO decomposition of multi-copy gene family trees into single-copy trees,
+//! see Willson et al., "DISCO: Species Tree Inference using Multicopy Gene
+//! Family Tree Decomposition" (Systematic Biology, 2022)
+use crate::tree::{Annotations, Tree};
+use fixedbitset::FixedBitSet;
+use std::collections::HashMap;
+
+/// The gene family tree as an unrooted tree, with the root of the input removed
+struct Unrooted {
+    /// (neighbor, edge) pairs of each node
+    adj: Vec<Vec<(usize, usize)>>,
+    lengths: Vec<f64>,
+    support: Vec<f64>,
+}
+
+impl Unrooted {
+    fn new(tree: &Tree) -> Self {
+        let n = tree.taxa.len();
+        let mut unrooted = Unrooted {
+            adj: vec![Vec::new(); n],
+            lengths: Vec::new(),
+            support: Vec::new(),
+        };
+        for v in 0..n {
+            if tree.is_root(v) {
+                continue;
+            }
+            let p = tree.parents[v] as usize;
+            if tree.fake_root && tree.is_root(p) {
+                // the two edges around a degree-2 root are one edge, whose length
+                // and support were already merged by the parser
+                let c = tree.firstchild[p] as usize;
+                if v != c {
+                    unrooted.add_edge(c, v, tree.lengths[v], tree.support[v]);
+                }
+            } else {
+                unrooted.add_edge(v, p, tree.lengths[v], tree.support[v]);
+            }
+        }
+        unrooted
+    }
+
+    fn add_edge(&mut self, u: usize, v: usize, length: f64, support: f64) {
+        let e = self.lengths.len();
+        self.adj[u].push((v, e));
+        self.adj[v].push((u, e));
+        self.lengths.push(length);
+        self.support.push(support);
+    }
+}
+
+/// Whether some species is found in two of the `sets`
+fn overlap<'a>(sets: impl Iterator<Item = &'a FixedBitSet>, ntaxa: usize) -> bool {
+    let mut seen = FixedBitSet::with_capacity(ntaxa);
+    for s in sets {
+        if !seen.is_disjoint(s) {
+            return true;
+        }
+        seen.union_with(s);
+    }
+    false
+}
+
+/// Finds the edge `(v, w)` whose rooting minimizes the number of duplication
+/// nodes, i.e., nodes with children sharing a species
+fn best_root(tree: &Tree, t: &Unrooted, ntaxa: usize) -> Option<(usize, usize)> {
+    let n = t.adj.len();
+    let start = (0..n)
+        .find(|&v| t.adj[v].len() > 1)
+        .or_else(|| (0..n).find(|&v| !t.adj[v].is_empty()))?;
+    // DFS order from `start`, with the index of the parent among the neighbors
+    let mut order = Vec::with_capacity(n);
+    let mut up = vec![usize::MAX; n];
+    let mut stack = vec![start];
+    let mut visited = vec![false; n];
+    visited[start] = true;
+    while let Some(v) = stack.pop() {
+        order.push(v);
+        for &(w, _) in &t.adj[v] {
+            if !visited[w] {
+                visited[w] = true;
+                up[w] = t.adj[w].iter().position(|&(x, _)| x == v).unwrap();
+                stack.push(w);
+            }
+        }
+    }
+    let own = |v: usize| {
+        let mut s = FixedBitSet::with_capacity(ntaxa);
+        if tree.taxa[v] >= 0 {
+            s.insert(tree.taxa[v] as usize);
+        }
+        s
+    };
+    // sets[v][k]: species beyond the k-th neighbor of v
+    // dups[v][k]: duplications beyond the k-th neighbor of v when rooted towards v
+    let mut sets: Vec<Vec<FixedBitSet>> = t
+        .adj
+        .iter()
+        .map(|it| vec![FixedBitSet::with_capacity(ntaxa); it.len()])
+        .collect();
+    let mut dups: Vec<Vec<usize>> = t.adj.iter().map(|it| vec![0; it.len()]).collect();
+    let is_dup = |sets: &[Vec<FixedBitSet>], v: usize, towards: usize| {
+        overlap(
+            sets[v]
+                .iter()
+                .enumerate()
+                .filter(|(k, _)| *k != towards)
+                .map(|(_, s)| s),
+            ntaxa,
+        ) as usize
+    };
+    let back = |v: usize| {
+        t.adj[t.adj[v][up[v]].0]
+            .iter()
+            .position(|&(x, _)| x == v)
+            .unwrap()
+    };
+    for &v in order.iter().rev().filter(|&&v| v != start) {
+        let mut s = own(v);
+        let mut d = is_dup(&sets, v, up[v]);
+        for (k, it) in sets[v].iter().enumerate() {
+            if k != up[v] {
+                s.union_with(it);
+                d += dups[v][k];
+            }
+        }
+        let (p, kv) = (t.adj[v][up[v]].0, back(v));
+        sets[p][kv] = s;
+        dups[p][kv] = d;
+    }
+    for &v in order.iter().filter(|&&v| v != start) {
+        let (p, kv) = (t.adj[v][up[v]].0, back(v));
+        let mut s = own(p);
+        let mut d = is_dup(&sets, p, kv);
+        for (k, it) in sets[p].iter().enumerate() {
+            if k != kv {
+                s.union_with(it);
+                d += dups[p][k];
+            }
+        }
+        sets[v][up[v]] = s;
+        dups[v][up[v]] = d;
+    }
+    // ties are broken by the traversal order
+    order
+        .iter()
+        .filter(|&&v| v != start)
+        .map(|&v| {
+            let (p, kv) = (t.adj[v][up[v]].0, back(v));
+            let root_dup = !sets[p][kv].is_disjoint(&sets[v][up[v]]) as usize;
+            (dups[p][kv] + dups[v][up[v]] + root_dup, v, p)
+        })
+        .min_by_key(|(cost, _, _)| *cost)
+        .map(|(_, v, p)| (v, p))
+}
+
+/// A subtree of the rooted gene family tree, after pruning the duplications below it
+struct Part {
+    node: usize,
+    children: Vec<Part>,
+    /// length and support of the edge above
+    length: f64,
+    support: f64,
+    species: FixedBitSet,
+    size: usize,
+}
+
+struct Decomposer<'a> {
+    tree: &'a Tree,
+    unrooted: Unrooted,
+    ntaxa: usize,
+    out: Vec<Part>,
+}
+
+impl<'a> Decomposer<'a> {
+    /// The subtree at `v` hanging from `parent`, splitting off the smaller
+    /// children of each duplication node, deepest first
+    fn prune(&mut self, v: usize, parent: usize, length: f64, support: f64) -> Option<Part> {
+        // postorder on an explicit stack of (part, parent, next neighbor), as
+        // ladder-like gene trees are too deep to recurse on
+        let mut stack = vec![(self.part(v, length, support), parent, 0)];
+        loop {
+            let (part, parent, next) = stack.last_mut().unwrap();
+            let v = part.node;
+            if let Some(&(w, e)) = self.unrooted.adj[v].get(*next) {
+                *next += 1;
+                if w != *parent {
+                    let child = self.part(w, self.unrooted.lengths[e], self.unrooted.support[e]);
+                    stack.push((child, v, 0));
+                }
+                continue;
+            }
+            let (part, _, _) = stack.pop().unwrap();
+            let resolved = self.resolve(part);
+            match stack.last_mut() {
+                Some((parent_part, _, _)) => parent_part.children.extend(resolved),
+                None => return resolved,
+            }
+        }
+    }
+
+    /// The part at `v` before its children are added
+    fn part(&self, v: usize, length: f64, support: f64) -> Part {
+        let mut part = Part {
+            node: v,
+            children: Vec::new(),
+            length,
+            support,
+            species: FixedBitSet::with_capacity(self.ntaxa),
+            size: 0,
+        };
+        if self.tree.taxa[v] >= 0 {
+            part.species.insert(self.tree.taxa[v] as usize);
+            part.size = 1;
+        }
+        part
+    }
+
+    fn resolve(&mut self, mut part: Part) -> Option<Part> {
+        if overlap(part.children.iter().map(|c| &c.species), self.ntaxa) {
+            // keep the child with the most leaves, the others become trees of their own
+            let largest = (0..part.children.len())
+                .rev()
+                .max_by_key(|&i| part.children[i].size)
+                .unwrap();
+            let kept = part.children.swap_remove(largest);
+            self.out.append(&mut part.children);
+            part.children.push(kept);
+        }
+        match part.children.len() {
+            0 if part.size == 0 => None,
+            0 => Some(part),
+            1 => {
+                // suppress the now unary node
+                let mut child = part.children.pop().unwrap();
+                child.length += part.length;
+                child.support = child.support.max(part.support);
+                Some(child)
+            }
+            _ => {
+                for c in &part.children {
+                    part.species.union_with(&c.species);
+                    part.size += c.size;
+                }
+                Some(part)
+            }
+        }
+    }
+}
+
+/// Roots a gene family tree to minimize duplications and decomposes it into
+/// single-copy trees with at least `min_leaves` leaves, and never fewer than
+/// two. `ntaxa` bounds the taxon ids of the leaves. Single-copy trees are
+/// returned as they are.
+pub fn decompose(tree: Tree, ntaxa: usize, min_leaves: usize) -> Vec<Tree> {
+    let min_leaves = min_leaves.max(2);
+    let mut seen = FixedBitSet::with_capacity(ntaxa);
+    let mut single_copy = true;
+    for &t in tree.taxa.iter().filter(|&&t| t >= 0) {
+        if seen.put(t as usize) {
+            single_copy = false;
+            break;
+        }
+    }
+    if single_copy {
+        let nleaves = tree.taxa.iter().filter(|&&t| t >= 0).count();
+        return if nleaves >= min_leaves {
+            vec![tree]
+        } else {
+            vec![]
+        };
+    }
+    let unrooted = Unrooted::new(&tree);
+    let (a, b) = match best_root(&tree, &unrooted, ntaxa) {
+        Some(it) => it,
+        None => return vec![],
+    };
+    let edge = unrooted.adj[a].iter().find(|&&(w, _)| w == b).unwrap().1;
+    let (length, support) = (unrooted.lengths[edge], unrooted.support[edge]);
+    let mut decomposer = Decomposer {
+        tree: &tree,
+        unrooted,
+        ntaxa,
+        out: Vec::new(),
+    };
+    let root = Part {
+        node: usize::MAX,
+        // the root splits the edge, whose whole length goes to one side as the
+        // two sides are merged again by `to_tree`
+        children: [(a, b, length), (b, a, 0.0)]
+            .iter()
+            .filter_map(|&(v, p, length)| decomposer.prune(v, p, length, support))
+            .collect(),
+        length: 0.0,
+        support: 0.0,
+        species: FixedBitSet::with_capacity(ntaxa),
+        size: 0,
+    };
+    if let Some(main) = decomposer.resolve(root) {
+        decomposer.out.push(main);
+    }
+    decomposer
+        .out
+        .into_iter()
+        .filter(|it| it.size >= min_leaves)
+        .map(|it| to_tree(&tree, it))
+        .collect()
+}
+
+/// Lays out a decomposed subtree in the same way as `parse_newick`
+fn to_tree(source: &Tree, root: Part) -> Tree {
+    let mut tree = Tree {
+        taxa: Vec::new(),
+        parents: Vec::new(),
+        support: Vec::new(),
+        lengths: Vec::new(),
+        firstchild: Vec::new(),
+        nextsib: Vec::new(),
+        childcount: Vec::new(),
+        fake_root: root.children.len() == 2,
+        root: 0,
+        annotations: HashMap::new(),
+        weight: source.weight,
+    };
+    let mut annotations: HashMap<usize, &Annotations> = HashMap::new();
+    // tree-wide comments sit on the root of the input
+    if let Some(it) = source.annotations.get(&source.root) {
+        annotations.insert(0, it);
+    }
+    let mut stack = vec![(root, -1i32)];
+    while let Some((part, parent)) = stack.pop() {
+        let i = tree.taxa.len();
+        if let Some(it) = source.annotations.get(&part.node) {
+            annotations.insert(i, it);
+        }
+        let leaf = part.children.is_empty();
+        tree.taxa
+            .push(if leaf { source.taxa[part.node] } else { -1 });
+        tree.parents.push(parent.max(0));
+        tree.support.push(part.support);
+        tree.lengths.push(part.length);
+        tree.firstchild.push(-1);
+        tree.nextsib.push(-1);
+        tree.childcount.push(part.children.len() as u32);
+        if parent >= 0 {
+            let p = parent as usize;
+            // children are laid out last to first, prepending keeps their order
+            tree.nextsib[i] = tree.firstchild[p];
+            tree.firstchild[p] = i as i32;
+        }
+        for child in part.children {
+            stack.push((child, i as i32));
+        }
+    }
+    if tree.fake_root {
+        let c = tree.firstchild[0] as usize;
+        let c2 = tree.nextsib[c] as usize;
+        let support = tree.support[c].max(tree.support[c2]);
+        let length = tree.lengths[c] + tree.lengths[c2];
+        for it in [c, c2] {
+            tree.support[it] = support;
+            tree.lengths[it] = length;
+        }
+    }
+    tree.annotations = annotations
+        .into_iter()
+        .map(|(k, v)| (k, v.clone()))
+        .collect();
+    tree
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::species::SpeciesMapping;
+    use crate::tree::{parse_newick, TaxonSet, UstarConfig};
+
+    /// The single-copy trees of a gene family tree whose leaves are
+    /// `species_copy`, in Newick
+    fn decomposed(newick: &str, min_leaves: usize) -> Vec<String> {
+        let mut taxon_set = TaxonSet::with_species(SpeciesMapping::Delimiter("_".to_string()));
+        let tree = parse_newick(&mut taxon_set, newick, &UstarConfig::default()).unwrap();
+        decompose(tree, taxon_set.len(), min_leaves)
+            .iter()
+            .map(|it| it.newick(&taxon_set))
+            .collect()
+    }
+
+    #[test]
+    fn single_copy_tree_is_kept() {
+        assert_eq!(decomposed("((A_1,B_1),(C_1,D_1));", 4).len(), 1);
+        assert!(decomposed("((A_1,B_1),C_1);", 4).is_empty());
+    }
+
+    #[test]
+    fn splits_duplications() {
+        // two complete copies
+        assert_eq!(
+            decomposed("(((A_1,B_1),C_1),((A_2,B_2),C_2));", 3),
+            ["((A:0,B:0):0,C:0);", "((A:0,B:0):0,C:0);"]
+        );
+        // the smaller copy is split off, and dropped below `min_leaves`
+        assert_eq!(
+            decomposed("((A_1,(B_1,(C_1,D_1))),(A_2,B_2));", 2),
+            ["(A:0,B:0);", "(A:0,(B:0,(C:0,D:0):0):0);"]
+        );
+        assert_eq!(
+            decomposed("((A_1,(B_1,(C_1,D_1))),(A_2,B_2));", 3),
+            ["(A:0,(B:0,(C:0,D:0):0):0);"]
+        );
+        // a duplicated leaf is pruned, the tree being rooted to minimize duplications
+        assert_eq!(
+            decomposed("(A_1,(A_2,(B_1,(C_1,D_1))));", 2),
+            ["((B:0,(C:0,D:0):0):0,A:0);"]
+        );
+    }
+
+    #[test]
+    fn deep_ladder_is_decomposed() {
+        // (A_0,(B_1,(C_2,(A_3,...)))), far deeper than the stack allows recursing
+        let n = 20000;
+        let mut newick = String::new();
+        for i in 0..n - 1 {
+            newick.push_str(&format!("({}_{},", ["A", "B", "C"][i % 3], i));
+        }
+        newick.push_str(&format!("D_{}", n - 1));
+        newick.push_str(&")".repeat(n - 1));
+        newick.push(';');
+        let mut taxon_set = TaxonSet::with_species(SpeciesMapping::Delimiter("_".to_string()));
+        let tree = parse_newick(&mut taxon_set, &newick, &UstarConfig::default()).unwrap();
+        let trees = decompose(tree, taxon_set.len(), 2);
+        assert!(!trees.is_empty());
+        for tree in trees {
+            let mut taxa: Vec<_> = tree.taxa.iter().filter(|&&t| t >= 0).collect();
+            let nleaves = taxa.len();
+            taxa.sort();
+            taxa.dedup();
+            assert!(nleaves >= 2 && taxa.len() == nleaves);
+        }
+    }
+
+    #[test]
+    fn single_leaf_is_dropped() {
+        assert!(decomposed("(A_1);", 1).is_empty());
+        assert!(decomposed("(A_1,A_2);", 1).is_empty());
+    }
+}
@@ -3,15 +3,21 @@
 #![allow(non_snake_case)]
 #![allow(dead_code)]
 
+use crate::aggregate::{Aggregation, PairSamples};
+use crate::newick::{quote_label, Lexer, Token};
 use crate::tree::*;
+use clap::ArgEnum;
 use ndarray::prelude::*;
 use ndarray::Array;
 use rayon::prelude::*;
 use std::cell::RefCell;
-use std::ffi::CString;
-use std::mem::size_of;
+use std::cmp::Ordering;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_int, c_void};
+use std::path::PathBuf;
 use std::ptr;
-use std::sync::Arc;
+use std::slice;
+use std::sync::Once;
 use thread_local::ThreadLocal;
 
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
@@ -19,45 +25,67 @@ include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 #[derive(Debug)]
 pub struct UstarState {
     pub dm: Array<f64, Ix2>,
-    pub mask: Array<u32, Ix2>,
+    pub mask: Array<f64, Ix2>, // sum of the weights of the trees covering each pair
     pub dim: usize,
     pub minted: bool,
-    pub temp: Option<Array<f64, Ix2>>,
-    pub norm_factor: f64,
     pub has_missing: bool,
+    /// the scale of each tree added in `Mode::NLength`, in the order they were added
+    pub scales: Vec<f64>,
+    /// mean distance between individuals of the same species, set by `flatten`
+    pub within: Vec<Option<f64>>,
+    pub aggregation: Aggregation,
+    /// the distances of each pair, kept unless the aggregation is the mean
+    pub samples: Option<PairSamples>,
 }
 
 impl UstarState {
     pub fn from_taxon_set(taxon_set: &TaxonSet, config: &UstarConfig) -> Self {
-        let n = taxon_set.len();
+        UstarState::new(taxon_set.len(), config)
+    }
+
+    /// A state holding an already averaged (flattened) matrix, `counts` being the
+    /// number of distances averaged for each pair, 1 if not given. Only the values
+    /// above the diagonal are used, pairs with a NaN distance or no count are missing.
+    pub fn from_distances(dm: &Array<f64, Ix2>, counts: Option<&Array<f64, Ix2>>) -> Self {
+        let n = dm.nrows();
+        let mut state = UstarState::new(n, &UstarConfig::default());
+        for i in 0..n {
+            for j in (i + 1)..n {
+                let count = counts.map_or(1.0, |it| it[[i, j]]);
+                if dm[[i, j]].is_nan() || count.is_nan() || count <= 0.0 {
+                    state.has_missing = true;
+                } else {
+                    state.dm[[i, j]] = dm[[i, j]];
+                    state.mask[[i, j]] = count;
+                }
+            }
+        }
+        state.minted = true;
+        state
+    }
+
+    fn new(n: usize, config: &UstarConfig) -> Self {
         let dm = Array::<f64, _>::zeros((n, n).f());
-        let mask = Array::<u32, _>::zeros((n, n).f());
+        let mask = Array::<f64, _>::zeros((n, n).f());
         UstarState {
             dm,
             mask,
             dim: n,
             minted: false,
-            temp: match config.mode {
-                Mode::NLength => Some(Array::<f64, _>::zeros((n, n).f())),
-                _ => None,
-            },
-            norm_factor: -1.0,
             has_missing: false,
+            scales: Vec::new(),
+            within: Vec::new(),
+            aggregation: config.aggregation,
+            samples: match config.aggregation {
+                Aggregation::Mean => None,
+                _ => Some(PairSamples::new(n, config.sketch_size)),
+            },
         }
     }
 
     pub fn from_tree_collection(tree_collection: &TreeCollection, config: &UstarConfig) -> Self {
         let mut state = UstarState::from_taxon_set(&tree_collection.taxon_set, config);
-        if config.mode == Mode::NLength {
-            state.temp = Some(Array::<f64, _>::zeros((state.dim, state.dim).f()));
-            for t in &tree_collection.trees {
-                add_to_matrix_with_temp(&mut state, t, config.mode);
-            }
-        } else {
-            for t in &tree_collection.trees {
-                add_to_matrix(&mut state, t, config.mode);
-            }
-        }
+        state.add_trees(&tree_collection.trees, config);
         state
     }
 
@@ -66,60 +94,108 @@ impl UstarState {
         config: &UstarConfig,
         nthreads: usize,
     ) -> Self {
-        let tls = Arc::new(ThreadLocal::new());
-        // FIXME: this is really a hack for efficient parallelization
-        let chunk_size_bound = match tree_collection.ntaxa() {
-            n if n <= 50 => 10000usize,
-            n if n <= 500 => 2000usize,
-            n if n <= 1000 => 500usize,
-            _ => 200usize,
-        };
-        let chunk_size = (tree_collection.trees.len() / nthreads + 1).max(chunk_size_bound);
-        let _ = &tree_collection
-            .trees
-            .par_chunks(chunk_size)
-            .for_each(|trees| {
-                let tls2 = tls.clone();
-                let state = tls2.get_or(|| {
-                    RefCell::new(UstarState::from_taxon_set(
-                        &tree_collection.taxon_set,
-                        config,
-                    ))
-                });
-                let mut borrowed = state.borrow_mut();
-                for t in trees {
-                    add_to_matrix(&mut borrowed, t, config.mode);
-                }
-            });
         let mut state = UstarState::from_taxon_set(&tree_collection.taxon_set, config);
-        Arc::try_unwrap(tls).unwrap().into_iter().for_each(|s| {
-            state.add_from(&s.borrow());
-        });
+        state.add_trees_par(
+            &tree_collection.taxon_set,
+            &tree_collection.trees,
+            config,
+            nthreads,
+        );
         state
     }
 
+    /// Enlarges the matrices to cover taxa added to the taxon set since the
+    /// state was created, keeping the distances accumulated so far
+    pub fn grow(&mut self, n: usize) {
+        if n <= self.dim {
+            return;
+        }
+        let d = self.dim;
+        let mut dm = Array::<f64, _>::zeros((n, n).f());
+        dm.slice_mut(s![..d, ..d]).assign(&self.dm);
+        self.dm = dm;
+        let mut mask = Array::<f64, _>::zeros((n, n).f());
+        mask.slice_mut(s![..d, ..d]).assign(&self.mask);
+        self.mask = mask;
+        if let Some(samples) = &mut self.samples {
+            samples.grow(n);
+        }
+        self.dim = n;
+    }
+
+    /// Accumulates `trees` into the state, which can already contain trees
+    /// analyzed with a different config (e.g., from another input file)
+    pub fn add_trees(&mut self, trees: &[Tree], config: &UstarConfig) {
+        for t in trees {
+            if let Some(scale) = self.add_tree(t, config) {
+                self.scales.push(scale);
+            }
+        }
+    }
+
+    /// Accumulates a single tree, the same way in the serial and parallel
+    /// paths, returning its scale in `Mode::NLength`
+    pub fn add_tree(&mut self, tree: &Tree, config: &UstarConfig) -> Option<f64> {
+        if config.mode == Mode::NLength {
+            Some(add_to_matrix_normalized(
+                self,
+                tree,
+                config.length_normalization,
+            ))
+        } else {
+            add_to_matrix(self, tree, config);
+            None
+        }
+    }
+
+    pub fn add_trees_par(
+        &mut self,
+        taxon_set: &TaxonSet,
+        trees: &[Tree],
+        config: &UstarConfig,
+        nthreads: usize,
+    ) {
+        let mut accumulator = ParallelAccumulator::new(nthreads);
+        accumulator.add_trees(taxon_set, trees, config);
+        accumulator.finish(self);
+    }
+
     pub fn flatten(&mut self) {
+        // pairs of individuals of the same species accumulate on the diagonal
+        self.within = (0..self.dim)
+            .map(|i| match self.mask[[i, i]] {
+                m if m <= 0.0 => None,
+                m => Some(self.dm[[i, i]] / m),
+            })
+            .collect();
+        self.dm.diag_mut().fill(0.0);
         for i in 0..self.dim {
             for j in (i + 1)..self.dim {
-                if self.mask[[i, j]] <= 0 {
+                if self.mask[[i, j]] <= 0.0 {
                     self.has_missing = true;
+                } else if let Some(d) = self
+                    .samples
+                    .as_mut()
+                    .and_then(|it| it.summarize(i, j, self.aggregation))
+                {
+                    self.dm[[i, j]] = d;
                 } else {
-                    self.dm[[i, j]] /= self.mask[[i, j]] as f64;
+                    self.dm[[i, j]] /= self.mask[[i, j]];
                 }
             }
         }
         self.minted = true;
     }
 
-    pub fn raw_tree(&mut self, taxon_set: &TaxonSet) -> String {
-        run_fastme(taxon_set, &self.dm, &FastMEConfig::default())
+    pub fn raw_tree(&mut self, taxon_set: &TaxonSet, fastme_config: &FastMEConfig) -> String {
+        run_fastme(&self.dm, fastme_config).newick(taxon_set)
     }
 
-    pub fn to_tree(&mut self, taxon_set: &TaxonSet) -> String {
+    pub fn to_tree(&mut self, taxon_set: &TaxonSet, fastme_config: &FastMEConfig) -> String {
         if !self.minted {
             self.flatten();
         }
-        self.raw_tree(taxon_set)
+        self.raw_tree(taxon_set, fastme_config)
     }
 
     pub fn add_from(&mut self, rhs: &UstarState) {
@@ -129,11 +205,91 @@ impl UstarState {
         rhs.mask.indexed_iter().for_each(|((i, j), v)| {
             self.mask[[i, j]] += v;
         });
+        if let (Some(samples), Some(other)) = (&mut self.samples, &rhs.samples) {
+            samples.merge(other);
+        }
     }
+
+    /// Records the distance `dist` between the taxa `l <= r` in a tree of weight `weight`
+    fn record(&mut self, l: usize, r: usize, dist: f64, weight: f64) {
+        self.dm[[l, r]] += dist * weight;
+        self.mask[[l, r]] += weight;
+        if l < r {
+            if let Some(samples) = &mut self.samples {
+                samples.push(l, r, dist, weight);
+            }
+        }
+    }
+}
+
+/// Accumulates gene trees in parallel, each thread into its own state. The
+/// states are kept across batches and summed into the main state once by
+/// `finish`, so that streaming many small batches stays parallel and cheap.
+pub struct ParallelAccumulator {
+    states: ThreadLocal<RefCell<ThreadState>>,
+    nthreads: usize,
+    /// number of trees added so far, the index of the next one
+    ntrees: usize,
 }
 
-pub fn add_to_matrix(state: &mut UstarState, tree: &Tree, mode: Mode) {
+/// The state of a thread and the scales of its trees by tree index
+type ThreadState = (UstarState, Vec<(usize, f64)>);
+
+impl ParallelAccumulator {
+    pub fn new(nthreads: usize) -> Self {
+        ParallelAccumulator {
+            states: ThreadLocal::new(),
+            nthreads,
+            ntrees: 0,
+        }
+    }
+
+    pub fn add_trees(&mut self, taxon_set: &TaxonSet, trees: &[Tree], config: &UstarConfig) {
+        let n = taxon_set.len();
+        let chunk_size = trees.len() / self.nthreads + 1;
+        let (states, start) = (&self.states, self.ntrees);
+        trees
+            .par_chunks(chunk_size)
+            .enumerate()
+            .for_each(|(k, trees)| {
+                let state =
+                    states.get_or(|| RefCell::new((UstarState::new(n, config), Vec::new())));
+                let mut borrowed = state.borrow_mut();
+                let (state, scales) = &mut *borrowed;
+                // the taxon set may have grown since the previous batch
+                state.grow(n);
+                for (i, t) in trees.iter().enumerate() {
+                    if let Some(scale) = state.add_tree(t, config) {
+                        scales.push((start + k * chunk_size + i, scale));
+                    }
+                }
+            });
+        self.ntrees += trees.len();
+    }
+
+    /// Sums the states of the threads into `state`
+    pub fn finish(self, state: &mut UstarState) {
+        // the scales are put back in the order of the trees
+        let mut scales = Vec::new();
+        for it in self.states {
+            let (mut other, mut s) = it.into_inner();
+            state.grow(other.dim);
+            other.grow(state.dim);
+            state.add_from(&other);
+            scales.append(&mut s);
+        }
+        scales.sort_unstable_by_key(|it| it.0);
+        state.scales.extend(scales.into_iter().map(|it| it.1));
+    }
+}
+
+pub fn add_to_matrix(state: &mut UstarState, tree: &Tree, config: &UstarConfig) {
     // a straightforward translation of the Treeswift logic
+    // without branch lengths, every branch has the mean length
+    let mean_length = match config.mode {
+        Mode::Hybrid => tree.mean_length(),
+        _ => None,
+    };
     // sparse vector of distances
     let mut leaf_dists = Vec::<Vec<(usize, f64)>>::new();
     leaf_dists.resize(tree.taxa.len(), Vec::new());
@@ -150,10 +306,14 @@ pub fn add_to_matrix(state: &mut UstarState, tree: &Tree, mode: Mode) {
                     calculated_root = true;
                 }
                 for e in leaf_dists.get_mut(c).unwrap() {
-                    e.1 += match mode {
-                        Mode::Support => tree.support[c],
+                    e.1 += match config.mode {
+                        Mode::Support => config.support_transform.apply(tree.support[c]),
                         Mode::Internode => 1.0,
                         Mode::NLength => tree.lengths[c],
+                        Mode::Hybrid => {
+                            mean_length.map_or(1.0, |m| tree.lengths[c] / m)
+                                * config.support_transform.apply(tree.support[c])
+                        }
                     };
                 }
             }
@@ -172,8 +332,7 @@ pub fn add_to_matrix(state: &mut UstarState, tree: &Tree, mode: Mode) {
                             let v_leaf = tree.taxa[v] as usize;
                             let l = std::cmp::min(u_leaf, v_leaf);
                             let r = std::cmp::max(u_leaf, v_leaf);
-                            state.dm[[l, r]] += dist;
-                            state.mask[[l, r]] += 1;
+                            state.record(l, r, dist, tree.weight);
                         }
                     }
                 }
@@ -192,8 +351,18 @@ pub fn add_to_matrix(state: &mut UstarState, tree: &Tree, mode: Mode) {
     }
 }
 
+/// How the branches of the species trees built for imputation are weighted;
+/// these trees carry no support
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ImputeWeight {
+    /// one per branch
+    Internode,
+    /// the branch length
+    Length,
+}
+
 // FIXME: use trait to DRY
-pub fn impute_matrix(state: &mut UstarState, tree: &Tree, mode: Mode) {
+pub fn impute_matrix(state: &mut UstarState, tree: &Tree, weight: ImputeWeight) {
     let mut leaf_dists = Vec::<Vec<(usize, f64)>>::new();
     leaf_dists.resize(tree.taxa.len(), Vec::new());
     for node in tree.postorder() {
@@ -209,10 +378,9 @@ pub fn impute_matrix(state: &mut UstarState, tree: &Tree, mode: Mode) {
                     calculated_root = true;
                 }
                 for e in leaf_dists.get_mut(c).unwrap() {
-                    e.1 += match mode {
-                        Mode::Support => tree.support[c],
-                        Mode::Internode => 1.0,
-                        Mode::NLength => tree.lengths[c],
+                    e.1 += match weight {
+                        ImputeWeight::Internode => 1.0,
+                        ImputeWeight::Length => tree.lengths[c],
                     };
                 }
             }
@@ -231,7 +399,7 @@ pub fn impute_matrix(state: &mut UstarState, tree: &Tree, mode: Mode) {
                             let v_leaf = tree.taxa[v] as usize;
                             let l = std::cmp::min(u_leaf, v_leaf);
                             let r = std::cmp::max(u_leaf, v_leaf);
-                            if state.mask[[l, r]] <= 0 {
+                            if state.mask[[l, r]] <= 0.0 {
                                 state.dm[[l, r]] = dist;
                             }
                         }
@@ -253,13 +421,17 @@ pub fn impute_matrix(state: &mut UstarState, tree: &Tree, mode: Mode) {
 }
 
 // FIXME: this is duplicating code
-// used only when mode is NLength
-pub fn add_to_matrix_with_temp(state: &mut UstarState, tree: &Tree, _: Mode) {
-    let temp = state.temp.as_mut().unwrap();
-    temp.fill(0.0);
+// used only when mode is NLength, each tree is rescaled by its own scale so
+// that the result depends neither on the order of the trees nor on threads
+pub fn add_to_matrix_normalized(
+    state: &mut UstarState,
+    tree: &Tree,
+    normalization: LengthNormalization,
+) -> f64 {
+    // (leaf, leaf, distance) for all pairs of leaves
+    let mut pairs = Vec::<(usize, usize, f64)>::new();
     let mut leaf_dists = Vec::<Vec<(usize, f64)>>::new();
     leaf_dists.resize(tree.taxa.len(), Vec::new());
-    let mut max_dis: f64 = 0.0;
     for node in tree.postorder() {
         if tree.is_leaf(node) {
             leaf_dists[node].push((node, 0.0));
@@ -286,14 +458,7 @@ pub fn add_to_matrix_with_temp(state: &mut UstarState, tree: &Tree, _: Mode) {
                         for j in 0..(leaves_c2.len()) {
                             let (u, ud) = leaves_c1[i];
                             let (v, vd) = leaves_c2[j];
-                            let dist = ud + vd;
-                            let u_leaf = tree.taxa[u] as usize;
-                            let v_leaf = tree.taxa[v] as usize;
-                            let l = std::cmp::min(u_leaf, v_leaf);
-                            let r = std::cmp::max(u_leaf, v_leaf);
-                            temp[[l, r]] += dist;
-                            max_dis = max_dis.max(dist);
-                            state.mask[[l, r]] += 1;
+                            pairs.push((u, v, ud + vd));
                         }
                     }
                 }
@@ -310,102 +475,430 @@ pub fn add_to_matrix_with_temp(state: &mut UstarState, tree: &Tree, _: Mode) {
             }
         }
     }
-    if state.norm_factor <= 0.0 {
-        state.norm_factor = max_dis;
+    let scale = length_scale(tree, &pairs, normalization);
+    for &(u, v, dist) in &pairs {
+        let u_leaf = tree.taxa[u] as usize;
+        let v_leaf = tree.taxa[v] as usize;
+        let l = std::cmp::min(u_leaf, v_leaf);
+        let r = std::cmp::max(u_leaf, v_leaf);
+        let dist = if scale > 0.0 { dist / scale } else { 0.0 };
+        state.record(l, r, dist, tree.weight);
+    }
+    scale
+}
+
+fn length_scale(
+    tree: &Tree,
+    pairs: &[(usize, usize, f64)],
+    normalization: LengthNormalization,
+) -> f64 {
+    match normalization {
+        LengthNormalization::None => 1.0,
+        LengthNormalization::Diameter => pairs.iter().map(|it| it.2).fold(0.0, f64::max),
+        LengthNormalization::Median => {
+            let mut dists: Vec<f64> = pairs.iter().map(|it| it.2).collect();
+            dists.sort_unstable_by(f64::total_cmp);
+            let m = dists.len() / 2;
+            match dists.len() {
+                0 => 0.0,
+                n if n % 2 == 0 => (dists[m - 1] + dists[m]) / 2.0,
+                _ => dists[m],
+            }
+        }
+        LengthNormalization::Midpoint => midpoint_depth(tree, pairs),
+        LengthNormalization::Total => tree.total_length(),
+    }
+}
+
+/// The mean distance from the leaves to the midpoint of the longest path
+fn midpoint_depth(tree: &Tree, pairs: &[(usize, usize, f64)]) -> f64 {
+    let (a, b, diameter) = match pairs.iter().max_by(|x, y| x.2.total_cmp(&y.2)) {
+        Some(&it) => it,
+        None => return 0.0,
+    };
+    // distances of each leaf from both ends of the longest path
+    let mut from_ends: Vec<Option<(f64, f64)>> = vec![None; tree.taxa.len()];
+    for &(u, v, d) in pairs {
+        for (x, y) in [(u, v), (v, u)] {
+            if y == a {
+                from_ends[x].get_or_insert((0.0, 0.0)).0 = d;
+            }
+            if y == b {
+                from_ends[x].get_or_insert((0.0, 0.0)).1 = d;
+            }
+        }
     }
-    if max_dis <= 0.0 {
-        return;
+    let from_ends: Vec<(f64, f64)> = from_ends.into_iter().flatten().collect();
+    let total: f64 = from_ends
+        .iter()
+        .map(|&(da, db)| {
+            // the path to the leaf leaves the longest path `t` away from `a`
+            let t = (da - db + diameter) / 2.0;
+            let h = (da + db - diameter) / 2.0;
+            h + (t - diameter / 2.0).abs()
+        })
+        .sum();
+    total / from_ends.len() as f64
+}
+
+/// How the species tree is built from the distance matrix
+#[derive(Copy, Clone, PartialEq, Eq, ArgEnum, Debug)]
+pub enum TreeMethod {
+    /// FastME's balanced minimum evolution taxon addition
+    Bal,
+    /// FastME's ordinary least squares taxon addition
+    Ols,
+    /// FastME's neighbor joining
+    Nj,
+    /// FastME's BioNJ
+    Bionj,
+    /// FastME's unweighted neighbor joining
+    Unj,
+    /// rapidNJ's neighbor joining instead of FastME, for very large taxon sets
+    Rapidnj,
+    /// neighbor joining in Rust instead of FastME
+    NativeNj,
+    /// BioNJ in Rust instead of FastME
+    NativeBionj,
+}
+
+/// How FastME builds the initial tree. MVR is not offered: the vendored FastME
+/// has no MVR dispatch in `ComputeTree`, and MVR needs the variances of the
+/// distances, which the distance matrix does not provide.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FastMEMethod {
+    /// balanced minimum evolution taxon addition
+    Bal,
+    /// ordinary least squares taxon addition
+    Ols,
+    Nj,
+    Bionj,
+    /// unweighted neighbor joining
+    Unj,
+}
+
+impl FastMEMethod {
+    fn code(self) -> u32 {
+        match self {
+            FastMEMethod::Bal => TaxAddBAL,
+            FastMEMethod::Ols => TaxAddOLS,
+            FastMEMethod::Nj => NJ,
+            FastMEMethod::Bionj => BIONJ,
+            FastMEMethod::Unj => UNJ,
+        }
     }
-    max_dis /= state.norm_factor;
-    temp.indexed_iter().for_each(|((i, j), v)| {
-        state.dm[[i, j]] += v / max_dis;
-    });
 }
 
+/// The nearest neighbor interchanges FastME improves the initial tree with
+#[derive(Copy, Clone, PartialEq, Eq, ArgEnum, Debug)]
+pub enum NniMethod {
+    None,
+    /// balanced minimum evolution
+    Bal,
+    /// ordinary least squares
+    Ols,
+}
+
+#[derive(Copy, Clone, Debug)]
 pub struct FastMEConfig {
-    use_nni: bool,
-    use_spr: bool,
+    pub method: FastMEMethod,
+    pub nni: NniMethod,
+    pub use_spr: bool,
 }
 
 impl FastMEConfig {
-    pub fn new(use_nni: bool, use_spr: bool) -> FastMEConfig {
-        FastMEConfig { use_nni, use_spr }
+    pub fn new(method: FastMEMethod, nni: NniMethod, use_spr: bool) -> FastMEConfig {
+        FastMEConfig {
+            method,
+            nni,
+            use_spr,
+        }
     }
 }
 
 impl Default for FastMEConfig {
     fn default() -> Self {
         FastMEConfig {
-            use_nni: true,
+            method: FastMEMethod::Bal,
+            nni: NniMethod::Bal,
             use_spr: true,
         }
     }
 }
 
-pub fn run_fastme(
-    taxon_set: &TaxonSet,
-    dm: &Array<f64, Ix2>,
-    fastme_config: &FastMEConfig,
-) -> String {
-    let size = taxon_set.len() as i32;
-    unsafe {
-        let mut A = initDoubleMatrix(2 * size - 2);
-        let D = initDoubleMatrix(2 * size - 2);
-        fillZeroMatrix(&mut A as *mut *mut *mut f64, 2 * size - 2);
-        for i in 0..size {
-            let ptr = D.offset(i as isize);
-            *ptr = mCalloc(size, size_of::<f64>() as u64) as *mut f64;
+/// Sets `isBoostrap`, FastME's only global state on our path, once before any
+/// tree is built. It silences the progress messages on stdout, and the NNI log
+/// otherwise written to a statistics file we never open.
+static FASTME_INIT: Once = Once::new();
+
+/// A square matrix allocated by FastME
+struct FastMEMatrix {
+    ptr: *mut *mut f64,
+    size: i32,
+}
+
+impl FastMEMatrix {
+    fn new(size: i32) -> Self {
+        FastMEMatrix {
+            ptr: unsafe { initDoubleMatrix(size) },
+            size,
         }
+    }
+
+    fn row_mut(&mut self, i: usize) -> &mut [f64] {
+        unsafe { slice::from_raw_parts_mut(*self.ptr.add(i), self.size as usize) }
+    }
+}
+
+impl Drop for FastMEMatrix {
+    fn drop(&mut self) {
+        unsafe { freeMatrix(self.ptr, self.size) }
+    }
+}
+
+/// The taxa handed to FastME, as nodes labeled by their index
+struct FastMESpecies(set); // terrible naming! Bad FastME!
+
+impl FastMESpecies {
+    fn new(size: usize) -> Self {
+        let mut species = FastMESpecies(set {
+            firstNode: ptr::null_mut(),
+            secondNode: ptr::null_mut(),
+        });
         for i in 0..size {
-            for j in (i + 1)..size {
-                let r1 = (*(D.offset(i as isize))).offset(j as isize);
-                let r2 = (*(D.offset(j as isize))).offset(i as isize);
-                let l = i as usize;
-                let r = j as usize;
-                *r1 = dm[[l, r]];
-                *r2 = dm[[l, r]];
+            let name = CString::new(i.to_string()).unwrap();
+            unsafe {
+                let v = makeNode(name.as_ptr(), -1);
+                (*v).index2 = i as i32;
+                addToSet(v, &mut species.0);
             }
         }
+        species
+    }
+}
 
-        let mut options = Options::default();
-        Set_Defaults_Input(&mut options);
-        options.use_NNI = if fastme_config.use_nni { 1 } else { 0 };
-        options.use_SPR = if fastme_config.use_spr { 1 } else { 0 };
-        options.method = TaxAddBAL as i32;
-        options.NNI = BALNNI as i32;
+impl Drop for FastMESpecies {
+    fn drop(&mut self) {
+        // the first set of the chain is ours, the others FastME's
+        unsafe {
+            freeNode(self.0.firstNode);
+            freeSet(self.0.secondNode);
+        }
+    }
+}
 
-        let mut species = set::default(); // terrible naming! Bad FastME!
-        species.firstNode = ptr::null_mut();
-        species.secondNode = ptr::null_mut();
+/// A tree built by FastME
+struct FastMETree(*mut tree);
 
-        for (i, _) in taxon_set.names.iter().enumerate() {
-            let name = CString::new(i.to_string()).unwrap();
-            let mut v = makeNode(name.as_ptr(), -1);
-            (*v).index2 = i as i32;
-            addToSet(v, &mut species as *mut set);
+impl FastMETree {
+    /// Converts the tree, its leaves labeled by the index of their taxon
+    fn to_tree(&self, size: usize) -> Tree {
+        let mut builder = TreeBuilder::new(size);
+        let mut children = Vec::new();
+        unsafe {
+            let root = (*self.0).root;
+            if leaf(root) != 0 {
+                // the trees of FastME hang from a leaf, joined here with the
+                // two subtrees below its neighbor as in FastME's Newick output
+                let e = (*root).leftEdge;
+                let w = (*e).head;
+                if leaf(w) != 0 {
+                    // two taxa, the length of their edge kept once
+                    children.push(((*w).index2 as usize, (*e).distance));
+                    children.push(((*root).index2 as usize, 0.0));
+                } else {
+                    children.push(fastme_subtree(&mut builder, (*w).leftEdge));
+                    children.push(fastme_subtree(&mut builder, (*w).rightEdge));
+                    children.push(((*root).index2 as usize, (*e).distance));
+                }
+            } else {
+                for e in [(*root).leftEdge, (*root).rightEdge, (*root).middleEdge] {
+                    if !e.is_null() {
+                        children.push(fastme_subtree(&mut builder, e));
+                    }
+                }
+            }
         }
+        builder.finish(&children)
+    }
+}
 
-        let mut t = ComputeTree(&mut options, D, A, &mut species, size, 8);
-        let mut nniCount: i32 = 0;
-        let mut sprCount: i32 = 0;
-        t = ImproveTree(
+impl Drop for FastMETree {
+    fn drop(&mut self) {
+        // `freeTree` only frees the subtree on the left of the root
+        unsafe {
+            let root = (*self.0).root;
+            for e in [&mut (*root).rightEdge, &mut (*root).middleEdge] {
+                if !e.is_null() {
+                    freeSubTree(*e);
+                    *e = ptr::null_mut();
+                }
+            }
+            freeTree(self.0);
+        }
+    }
+}
+
+/// Adds the subtree below the FastME edge `e` to `builder`, returning its root
+/// and the length of `e`. Iterative, as caterpillar-like trees can be deep.
+unsafe fn fastme_subtree(builder: &mut TreeBuilder, e: *mut edge) -> (usize, f64) {
+    let mut stack = vec![(e, false)];
+    let mut built: Vec<(usize, f64)> = Vec::new();
+    while let Some((e, expanded)) = stack.pop() {
+        let v = (*e).head;
+        if leaf(v) != 0 {
+            built.push(((*v).index2 as usize, (*e).distance));
+        } else if expanded {
+            let right = built.pop().unwrap();
+            let left = built.pop().unwrap();
+            built.push((builder.join(&[left, right]), (*e).distance));
+        } else {
+            stack.push((e, true));
+            stack.push(((*v).rightEdge, false));
+            stack.push(((*v).leftEdge, false));
+        }
+    }
+    built.pop().unwrap()
+}
+
+/// Builds the species tree from the upper triangle of the distance matrix by
+/// FastME, the leaves being the indices of the taxa. The matrices, taxa and
+/// tree handed to FastME are freed on drop, and several trees can be built at
+/// once from different threads, e.g. for bootstrap replicates.
+pub fn run_fastme(dm: &Array<f64, Ix2>, fastme_config: &FastMEConfig) -> Tree {
+    FASTME_INIT.call_once(|| unsafe { isBoostrap = 1 });
+    let size = dm.shape()[0];
+    let mut d = FastMEMatrix::new(size as i32);
+    for l in 0..size {
+        for r in (l + 1)..size {
+            d.row_mut(l)[r] = dm[[l, r]];
+            d.row_mut(r)[l] = dm[[l, r]];
+        }
+    }
+    let a = FastMEMatrix::new(2 * size as i32 - 2);
+    let mut species = FastMESpecies::new(size);
+
+    // set by hand, as `Set_Defaults_Input` also resets the global verbosity
+    let mut options = Options::default();
+    options.use_NNI = if fastme_config.nni == NniMethod::None {
+        0
+    } else {
+        1
+    };
+    options.use_SPR = if fastme_config.use_spr { 1 } else { 0 };
+    options.method = fastme_config.method.code() as i32;
+    options.NNI = match fastme_config.nni {
+        NniMethod::Ols => OLSNNI,
+        _ => BALNNI,
+    } as i32;
+
+    let mut nni_count: i32 = 0;
+    let mut spr_count: i32 = 0;
+    let t = unsafe {
+        let t = ComputeTree(&mut options, d.ptr, a.ptr, &mut species.0, size as i32, 8);
+        // frees `t` unless it stays the best tree
+        FastMETree(ImproveTree(
             &mut options,
             t,
-            D,
-            A,
-            &mut nniCount,
-            &mut sprCount,
+            d.ptr,
+            a.ptr,
+            &mut nni_count,
+            &mut spr_count,
             options.fpO_stat_file,
+        ))
+    };
+    t.to_tree(size)
+}
+
+/// Settings of the rapidNJ backend
+#[derive(Clone, Debug, Default)]
+pub struct RapidNJConfig {
+    /// keep the distance matrix on disk in this directory instead of in memory
+    pub cache_dir: Option<PathBuf>,
+    /// the memory used with `cache_dir` in megabytes, by default 80% of the
+    /// physical memory
+    pub memory_mb: Option<usize>,
+}
+
+/// Neighbor joining on the flattened distance matrix by rapidNJ
+pub fn run_rapidnj(
+    taxon_set: &TaxonSet,
+    dm: &Array<f64, Ix2>,
+    rapidnj_config: &RapidNJConfig,
+) -> Tree {
+    let size = taxon_set.len();
+    let dm = dm.slice(s![..size, ..size]);
+    let cache_dir = rapidnj_config
+        .cache_dir
+        .as_ref()
+        .map(|it| CString::new(it.to_string_lossy().into_owned()).unwrap());
+    let newick = unsafe {
+        let ptr = rapidnj_tree(
+            Some(rapidnj_row),
+            &dm as *const ArrayView2<f64> as *mut c_void,
+            size as i32,
+            cache_dir.as_ref().map_or(ptr::null(), |it| it.as_ptr()),
+            rapidnj_config.memory_mb.unwrap_or(0) as i64,
         );
-        let mut tree_output = vec![0u8; (size << 10) as usize]; //Vec::<u8>::with_capacity((size << 10) as usize);
-        NewickPrintTreeStr(t, tree_output.as_mut_ptr() as *mut i8, 2);
-        let result = CString::from_vec_unchecked(tree_output);
-        let s = result.to_str().unwrap().to_owned();
-        let translated = translate_newick(taxon_set, &s);
-        freeMatrix(A, 2 * size - 2);
-        freeMatrix(D, 2 * size - 2); // there are probably other leaks present
-        translated
+        let s = CStr::from_ptr(ptr).to_str().unwrap().to_owned();
+        rapidnj_free(ptr);
+        s
+    };
+    rapidnj_to_tree(size, &newick)
+}
+
+/// Fills `row` with the distances of taxon `i`, read from the upper triangle
+/// of the distance matrix behind `data`
+unsafe extern "C" fn rapidnj_row(data: *mut c_void, i: c_int, row: *mut f64) {
+    let dm = &*(data as *const ArrayView2<f64>);
+    let i = i as usize;
+    let row = slice::from_raw_parts_mut(row, dm.nrows());
+    for (j, d) in row.iter_mut().enumerate() {
+        *d = match i.cmp(&j) {
+            Ordering::Less => dm[[i, j]],
+            Ordering::Greater => dm[[j, i]],
+            Ordering::Equal => 0.0,
+        };
+    }
+}
+
+/// Reads rapidNJ's Newick, whose leaves are labeled by their taxon id
+fn rapidnj_to_tree(size: usize, newick: &str) -> Tree {
+    let mut builder = TreeBuilder::new(size);
+    let mut stack: Vec<Vec<(usize, f64)>> = vec![];
+    let mut node = None;
+    let mut length = 0.0;
+    let mut after_colon = false;
+    for token in Lexer::new(newick) {
+        let (_, token) = token.expect("rapidNJ writes valid Newick");
+        match token {
+            Token::Open => stack.push(vec![]),
+            Token::Label(label) if after_colon => {
+                length = label.parse().expect("rapidNJ writes numeric lengths");
+                after_colon = false;
+            }
+            // the label of an internal node is a bootstrap value
+            Token::Label(label) if node.is_none() => {
+                node = Some(label.parse().expect("rapidNJ labels leaves by index"));
+            }
+            Token::Label(_) => {}
+            Token::Colon => after_colon = true,
+            Token::Comma | Token::Close => {
+                let children = stack.last_mut().expect("rapidNJ writes valid Newick");
+                children.push((node.take().expect("rapidNJ writes valid Newick"), length));
+                length = 0.0;
+                if token == Token::Close {
+                    let children = stack.pop().unwrap();
+                    if stack.is_empty() {
+                        return builder.finish(&children);
+                    }
+                    node = Some(builder.join(&children));
+                }
+            }
+            Token::Semicolon | Token::Comment(_) => {}
+        }
     }
+    panic!("rapidNJ writes valid Newick")
 }
 
 pub fn translate_newick(taxon_set: &TaxonSet, newick: &str) -> String {
@@ -451,7 +944,7 @@ pub fn translate_newick(taxon_set: &TaxonSet, newick: &str) -> String {
             }
             if !buf.ends_with(')') {
                 let leaf_ix = ts.parse::<usize>().unwrap();
-                buf.push_str(&taxon_set.names[leaf_ix]);
+                buf.push_str(&quote_label(&taxon_set.names[leaf_ix]));
             } else {
                 buf.push_str(&ts);
             }
@@ -462,6 +955,7 @@ pub fn translate_newick(taxon_set: &TaxonSet, newick: &str) -> String {
 
 #[cfg(test)]
 pub mod tests {
+    use super::*;
     use std::path::PathBuf;
     pub fn avian_tree() -> PathBuf {
         let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
@@ -469,4 +963,46 @@ pub mod tests {
         d.push("avian.tre");
         d
     }
+
+    #[test]
+    fn rapidnj_finds_three_cherries() {
+        let mut taxon_set = TaxonSet::new();
+        for name in ["A", "B", "C", "D", "E", "F"] {
+            taxon_set.request(name.to_string());
+        }
+        // the distances between the leaves of `((A:1,B:2):1,(C:1,D:2):1,(E:1,F:2):1);`
+        let dm = arr2(&[
+            [0.0, 3.0, 4.0, 5.0, 4.0, 5.0],
+            [0.0, 0.0, 5.0, 6.0, 5.0, 6.0],
+            [0.0, 0.0, 0.0, 3.0, 4.0, 5.0],
+            [0.0, 0.0, 0.0, 0.0, 5.0, 6.0],
+            [0.0, 0.0, 0.0, 0.0, 0.0, 3.0],
+            [0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+        ]);
+        let tree = run_rapidnj(&taxon_set, &dm, &RapidNJConfig::default());
+        let leaf = |t: i32| tree.taxa.iter().position(|&it| it == t).unwrap();
+        for (a, b) in [(0, 1), (2, 3), (4, 5)] {
+            assert_eq!(tree.parents[leaf(a)], tree.parents[leaf(b)]);
+        }
+        assert_eq!(tree.taxa.iter().filter(|&&t| t >= 0).count(), 6);
+    }
+    #[test]
+    fn length_scales() {
+        let mut taxon_set = TaxonSet::new();
+        let config = UstarConfig::default();
+        let tree = parse_newick(&mut taxon_set, "((A:1,B:3):1,(C:2,D:4):2);", &config).unwrap();
+        // the longest path B-D is split 5:5 one unit below the root towards D,
+        // leaving A and C 3 away from the midpoint
+        for (normalization, scale) in [
+            (LengthNormalization::None, 1.0),
+            (LengthNormalization::Diameter, 10.0),
+            (LengthNormalization::Median, 7.0),
+            (LengthNormalization::Midpoint, 4.0),
+            (LengthNormalization::Total, 13.0),
+        ] {
+            let mut state = UstarState::from_taxon_set(&taxon_set, &config);
+            let found = add_to_matrix_normalized(&mut state, &tree, normalization);
+            assert_eq!(found, scale, "{:?}", normalization);
+        }
+    }
 }
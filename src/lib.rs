@@ -0,0 +1,17 @@
+//! ASTRID-like species tree estimation from gene trees, as used by `wastrid`.
+//! The distance matrix is accumulated in [`internode::UstarState`] from the
+//! trees of [`tree`], and the species tree built by FastME, rapidNJ or the
+//! native builders of [`bionj`].
+pub mod aggregate;
+pub mod bionj;
+pub mod checkpoint;
+pub mod disco;
+pub mod internode;
+pub mod matrix;
+pub mod newick;
+pub mod nexus;
+pub mod species;
+pub mod support;
+pub mod tree;
+pub mod upgma;
+pub mod weights;
@@ -1,53 +1,268 @@
-mod internode;
-mod tree;
-mod upgma;
-use clap::{Parser, ArgEnum};
-use internode::*;
+use clap::{Parser, ArgEnum, Subcommand};
+use internode::aggregate::Aggregation;
+use internode::bionj::{bionj, bionj_star, neighbor_joining};
+use internode::checkpoint::{merge_states, write_state, StateInfo};
+use internode::internode::*;
+use internode::matrix::{write_matrix, LabeledMatrix, MatrixFormat};
+use internode::species::SpeciesMapping;
+use internode::support::SupportTransform;
+use internode::tree::{LengthNormalization, Mode, TaxonFilter, TaxonSet, Tree, TreeCollection, UstarConfig};
+use internode::upgma::upgma_star;
+use internode::weights::GeneWeights;
+use regex::Regex;
 use std::fs::{self, File};
+use std::io::{BufWriter, Write};
 use std::path::PathBuf;
 use tracing::{info, warn};
-use tree::{Mode, TreeCollection, UstarConfig};
-use upgma::upgma_star;
-use crate::tree::parse_newick;
-use ndarray_npy::WriteNpyExt;
+use std::path::Path;
+
+/// How missing distances are handled before building the species tree
+#[derive(Debug, ArgEnum, Clone, Copy, PartialEq, Eq)]
+enum Imputation {
+    /// impute them from a UPGMA* tree, then from a FastME tree, as in ASTRID
+    Uns,
+    /// build the tree by BioNJ* straight from the incomplete matrix
+    None,
+}
 
 #[derive(Debug, ArgEnum, Clone, Copy)]
 enum Preset {
     Vanilla,
     Abayes,
     HundredBootstrap,
+    HybridBootstrap,
+}
+
+/// An input file with optional per-file settings, e.g. `genes.tre:0-100`
+#[derive(Debug, Clone)]
+struct InputSpec {
+    path: PathBuf,
+    preset: Option<Preset>,
+    mode: Option<Mode>,
+    bounds: Option<(f64, f64)>,
+}
+
+impl InputSpec {
+    /// The global configuration overridden by the per-file settings
+    fn config(&self, base: &UstarConfig) -> UstarConfig {
+        let mut config = base.clone();
+        if let Some(preset) = self.preset {
+            apply_preset(&mut config, preset);
+        }
+        if let Some(mode) = self.mode {
+            config.mode = mode;
+        }
+        if let Some((lower, upper)) = self.bounds {
+            config.lower_bound = lower;
+            config.upper_bound = upper;
+        }
+        config
+    }
 }
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
-struct Args {
-    /// Path to the input newline delimited gene trees
-    #[clap(short, long)]
-    input: PathBuf,
+#[clap(subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[clap(flatten)]
+    args: Args,
+    #[clap(flatten)]
+    finish: FinishArgs,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Accumulate gene trees into a partial state, to be combined by `merge`
+    Accumulate {
+        #[clap(flatten)]
+        args: Box<Args>,
+        /// Path to the output partial state
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Sum partial states written by `accumulate` and infer the species tree
+    Merge {
+        /// Paths to the partial states
+        #[clap(required = true)]
+        states: Vec<PathBuf>,
+        #[clap(flatten)]
+        finish: FinishArgs,
+    },
+    /// Infer the species tree from a distance matrix, e.g. one written by `--only-distances`
+    FromMatrix {
+        /// Path to the matrix, in PHYLIP (square or lower-triangular, NA for missing
+        /// pairs) or NumPy `.npz` with `dm`, `names` and optionally `mask` arrays
+        matrix: PathBuf,
+        /// Path to a PHYLIP matrix of the count of each pair, pairs with no count
+        /// being missing; defaults to the `mask` of an `.npz` matrix
+        #[clap(long)]
+        counts: Option<PathBuf>,
+        #[clap(flatten)]
+        finish: FinishArgs,
+    },
+}
+
+/// Options of the steps after the distance matrix is accumulated
+#[derive(clap::Args, Debug)]
+struct FinishArgs {
     /// Path to the output species tree topology
     #[clap(short, long)]
     output: Option<PathBuf>,
+    /// Handling of missing distances: impute them, or skip them with BioNJ*
+    #[clap(long, arg_enum, default_value_t = Imputation::Uns)]
+    impute: Imputation,
+    /// Experimental option, do not use yet.
+    #[clap(long)]
+    length_impute: bool,
+    /// Only output the average distance matrix
+    #[clap(long)]
+    only_distances: bool,
+    /// Format of the distance matrix output by `--only-distances`; all but npy
+    /// label it with the taxon names, and the text formats write NA for missing pairs
+    #[clap(long, arg_enum, default_value_t = MatrixFormat::Npy)]
+    distances_format: MatrixFormat,
+    /// Path to write the mean distance between individuals of the same species
+    #[clap(long)]
+    within_species: Option<PathBuf>,
+    /// Method building the species tree from the distance matrix
+    #[clap(long, arg_enum, default_value_t = TreeMethod::Bal)]
+    tree_method: TreeMethod,
+    /// Nearest neighbor interchanges improving the initial species tree
+    #[clap(long, arg_enum, default_value_t = NniMethod::Bal)]
+    nni: NniMethod,
+    /// Also improve the species tree by subtree pruning and regrafting (the default)
+    #[clap(long, overrides_with = "no-spr")]
+    spr: bool,
+    /// Skip subtree pruning and regrafting
+    #[clap(long, overrides_with = "spr")]
+    no_spr: bool,
+    /// With `--tree-method rapidnj`, keep the distance matrix on disk in this
+    /// directory, for matrices that do not fit in memory
+    #[clap(long)]
+    rapidnj_cache: Option<PathBuf>,
+    /// Memory in MB used by rapidNJ with `--rapidnj-cache`, by default 80% of
+    /// the physical memory
+    #[clap(long)]
+    rapidnj_memory: Option<usize>,
+}
+
+impl FinishArgs {
+    fn rapidnj_config(&self) -> RapidNJConfig {
+        RapidNJConfig {
+            cache_dir: self.rapidnj_cache.clone(),
+            memory_mb: self.rapidnj_memory,
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct Args {
+    /// Path to the input gene trees, newline delimited Newick or NEXUS, optionally
+    /// gzip/zstd/bzip2 compressed. Use `-` for stdin. Can be repeated, each file
+    /// optionally followed by its own comma separated bounds, mode or preset,
+    /// e.g. `-i raxml.tre:0-100 -i iqtree.tre:abayes`
+    #[clap(
+        short,
+        long,
+        required = true,
+        multiple_occurrences = true,
+        parse(try_from_str = parse_input)
+    )]
+    input: Vec<InputSpec>,
     /// Analysis mode
     #[clap(short, long, arg_enum, default_value_t = Mode::Support)]
     mode: Mode,
-    /// Range of the support threshold
-    #[clap(short, long, parse(try_from_str = parse_bounds), default_value="0.0-1.0")]
-    bounds: (f64, f64),
+    /// Per gene tree scale that path lengths are divided by in n-length mode
+    #[clap(long, arg_enum, default_value_t = LengthNormalization::Diameter)]
+    length_normalization: LengthNormalization,
+    /// Path to write the scale of each gene tree in n-length mode
+    #[clap(long)]
+    length_scales: Option<PathBuf>,
+    /// Summary of the distances of each pair across gene trees: mean, median or
+    /// trimmed[:FRACTION] for the mean without FRACTION (default 0.1) of the weight at both ends
+    #[clap(long, default_value = "mean")]
+    aggregation: Aggregation,
+    /// Keep at most this many distances per pair for median and trimmed aggregation,
+    /// approximating them in bounded memory instead of keeping all distances
+    #[clap(long)]
+    sketch_size: Option<usize>,
+    /// Range of the support threshold, 0.0-1.0 by default
+    #[clap(short, long, parse(try_from_str = parse_bounds))]
+    bounds: Option<(f64, f64)>,
+    /// Guess the range of the support of each input file from the support values found,
+    /// unless bounds are given for the file or by -b or --preset
+    #[clap(long)]
+    auto_bounds: bool,
+    /// Edge weight as a function of the support rescaled to [0, 1]: linear, power:K,
+    /// logistic[:K[:MID]], threshold:T or neglog[:CAP] for -ln(1-s)
+    #[clap(long, default_value = "linear")]
+    support_transform: SupportTransform,
+    /// Which of the `/`-separated values of a support label to use, e.g. 2 for UFBoot
+    /// in IQ-TREE's SH-aLRT/UFBoot labels
+    #[clap(long, default_value_t = 1usize)]
+    support_field: usize,
     /// Number of threads. Currently only useful for very large (2000+ genes and 50+ species) datasets.
     #[clap(short, long, default_value_t = 1usize)]
     threads: usize,
-    /// Iteration method for distance imputation
-    #[clap(long, default_value_t = String::from("uns"))]
-    impute: String,
+    /// Number of gene trees held in memory at once; each batch is added to the
+    /// distance matrix and then dropped
+    #[clap(long, default_value_t = 10000usize)]
+    batch_size: usize,
     /// Preset for the analysis
     #[clap(long, arg_enum)]
     preset: Option<Preset>,
-    /// Experimental option, do not use yet.
+    /// Log and drop malformed gene trees instead of aborting
     #[clap(long)]
-    length_impute: bool,
-    /// Only output the average distance matrix
+    skip_invalid: bool,
+    /// Mapping from the gene tree leaves (individuals) to species, either in the
+    /// ASTRAL `-a` format (`species:ind1,ind2`) or one `individual species` per line
+    #[clap(short = 'a', long, conflicts_with_all = &["species-regex", "species-delimiter"])]
+    mapping: Option<PathBuf>,
+    /// Regex extracting the species from a leaf label, its first capture group if any
+    #[clap(long, parse(try_from_str = Regex::new), conflicts_with = "species-delimiter")]
+    species_regex: Option<Regex>,
+    /// The species of a leaf label is everything before the last occurrence of
+    /// this delimiter, e.g. `_` for `Homo_sapiens_1`
     #[clap(long)]
-    only_distances: bool,
+    species_delimiter: Option<String>,
+    /// Weights of the gene trees, one per line in input order, or `id weight` per line
+    /// with the id being the NEXUS tree name or the 1-based index of the gene tree
+    #[clap(short, long)]
+    weights: Option<PathBuf>,
+    /// Keep the `[...]` comments of the gene trees instead of skipping them, taking
+    /// the support of a branch from its `[&support=...]` or NHX `B=` annotation
+    #[clap(long)]
+    keep_comments: bool,
+    /// Weight the gene trees by their `[&W ...]` annotation
+    #[clap(long)]
+    weight_annotations: bool,
+    /// Path to a list of taxa (one per line) to prune from the gene trees
+    #[clap(long, conflicts_with = "include-taxa")]
+    exclude_taxa: Option<PathBuf>,
+    /// Path to a list of taxa (one per line) to keep in the gene trees, pruning the others
+    #[clap(long)]
+    include_taxa: Option<PathBuf>,
+    /// Drop gene trees with fewer taxa than this, after pruning
+    #[clap(long, default_value_t = 0usize)]
+    min_taxa: usize,
+    /// Drop gene trees covering less than this fraction of all taxa, after pruning
+    #[clap(long)]
+    min_occupancy: Option<f64>,
+    /// Contract gene tree branches with support below this value (in the units of
+    /// the input support) into polytomies
+    #[clap(long)]
+    contract_support: Option<f64>,
+    /// Contract gene tree branches shorter than this into polytomies
+    #[clap(long)]
+    contract_length: Option<f64>,
+    /// Decompose multi-copy gene family trees into single-copy trees (DISCO)
+    #[clap(long)]
+    disco: bool,
+    /// Minimum number of leaves of the single-copy trees kept by `--disco`, at least 2
+    #[clap(long, default_value_t = 4usize)]
+    disco_min_taxa: usize,
 }
 
 fn parse_bounds(s: &str) -> Result<(f64, f64), String> {
@@ -56,12 +271,12 @@ fn parse_bounds(s: &str) -> Result<(f64, f64), String> {
         .next()
         .ok_or("missing first bound")?
         .parse::<f64>()
-        .expect("invalid first bound");
+        .map_err(|_| "invalid first bound")?;
     let b = parts
         .next()
         .ok_or("missing second bound")?
         .parse::<f64>()
-        .expect("invalid second bound");
+        .map_err(|_| "invalid second bound")?;
     if a > b {
         Err("first bound must be less than second bound".to_string())
     } else {
@@ -69,98 +284,388 @@ fn parse_bounds(s: &str) -> Result<(f64, f64), String> {
     }
 }
 
-fn args_to_config(args: &Args) -> UstarConfig {
-    UstarConfig {
-        upper_bound: args.bounds.1,
-        lower_bound: args.bounds.0,
-        mode: args.mode,
+fn parse_input(s: &str) -> Result<InputSpec, String> {
+    let mut spec = InputSpec {
+        path: PathBuf::from(s),
+        preset: None,
+        mode: None,
+        bounds: None,
+    };
+    // a suffix that does not parse as settings is just part of the path
+    if let Some((path, settings)) = s.rsplit_once(':') {
+        let mut parsed = spec.clone();
+        parsed.path = PathBuf::from(path);
+        for setting in settings.split(',') {
+            if let Ok(bounds) = parse_bounds(setting) {
+                parsed.bounds = Some(bounds);
+            } else if let Ok(mode) = Mode::from_str(setting, true) {
+                parsed.mode = Some(mode);
+            } else if let Ok(preset) = Preset::from_str(setting, true) {
+                parsed.preset = Some(preset);
+            } else {
+                return Ok(spec);
+            }
+        }
+        spec = parsed;
     }
+    Ok(spec)
 }
 
-fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
-    tracing_subscriber::fmt()
-        .with_writer(std::io::stderr)
-        .init();
-    let mut config = args_to_config(&args);
-    match args.preset {
-        Some(Preset::Vanilla) => {
+fn apply_preset(config: &mut UstarConfig, preset: Preset) {
+    match preset {
+        Preset::Vanilla => {
             config.mode = Mode::Internode;
-        },
-        Some(Preset::Abayes) => {
+        }
+        Preset::Abayes => {
             config.mode = Mode::Support;
             config.lower_bound = 0.333;
             config.upper_bound = 1.0;
-        },
-        Some(Preset::HundredBootstrap) => {
+        }
+        Preset::HundredBootstrap => {
             config.mode = Mode::Support;
             config.lower_bound = 0.0;
             config.upper_bound = 100.0;
-        },
-        None => {},
+        }
+        Preset::HybridBootstrap => {
+            config.mode = Mode::Hybrid;
+            config.lower_bound = 0.0;
+            config.upper_bound = 100.0;
+        }
+    }
+}
+
+fn species_mapping(args: &Args) -> anyhow::Result<Option<SpeciesMapping>> {
+    Ok(if let Some(path) = &args.mapping {
+        Some(SpeciesMapping::from_file(path)?)
+    } else if let Some(re) = &args.species_regex {
+        Some(SpeciesMapping::Regex(re.clone()))
+    } else {
+        args.species_delimiter.clone().map(SpeciesMapping::Delimiter)
+    })
+}
+
+/// An empty collection set up with the species mapping, taxon filter and weights
+fn new_collection(args: &Args) -> anyhow::Result<TreeCollection> {
+    let mut trees = match species_mapping(args)? {
+        Some(mapping) => TreeCollection::with_species(mapping),
+        None => TreeCollection::new(),
+    };
+    if let Some(path) = &args.exclude_taxa {
+        trees.taxon_set.set_filter(TaxonFilter::from_file(path, false)?);
+    } else if let Some(path) = &args.include_taxa {
+        trees.taxon_set.set_filter(TaxonFilter::from_file(path, true)?);
     }
+    if let Some(path) = &args.weights {
+        trees.weights = Some(GeneWeights::from_file(path)?);
+    }
+    Ok(trees)
+}
+
+fn args_to_config(args: &Args) -> UstarConfig {
+    let (lower_bound, upper_bound) = args.bounds.unwrap_or((0.0, 1.0));
+    UstarConfig {
+        upper_bound,
+        lower_bound,
+        mode: args.mode,
+        support_transform: args.support_transform,
+        length_normalization: args.length_normalization,
+        aggregation: args.aggregation,
+        sketch_size: args.sketch_size,
+        skip_invalid: args.skip_invalid,
+        keep_comments: args.keep_comments,
+        support_field: args.support_field.saturating_sub(1),
+        weight_annotations: args.weight_annotations,
+        min_taxa: args.min_taxa,
+        contract_support: args.contract_support,
+        contract_length: args.contract_length,
+        disco: args.disco.then_some(args.disco_min_taxa),
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
+    match cli.command {
+        None => {
+            let (mut trees, mut ustar, _) = accumulate(&cli.args)?;
+            finish(&mut ustar, &mut trees.taxon_set, &cli.finish)
+        }
+        Some(Command::Accumulate { args, output }) => {
+            let (trees, ustar, info) = accumulate(&args)?;
+            if let Some(out) = output {
+                let writer = BufWriter::new(File::create(out)?);
+                write_state(writer, &trees.taxon_set, &ustar, &info)?;
+            } else {
+                write_state(std::io::stdout().lock(), &trees.taxon_set, &ustar, &info)?;
+            }
+            Ok(())
+        }
+        Some(Command::Merge { states, finish: finish_args }) => {
+            let (mut taxon_set, mut ustar, info) = merge_states(&states)?;
+            info!(
+                "merged {} partial states of {} gene trees with {} taxa",
+                states.len(),
+                info.ngenes,
+                taxon_set.len()
+            );
+            if taxon_set.len() < 2 {
+                anyhow::bail!("fewer than two taxa in the partial states");
+            }
+            finish(&mut ustar, &mut taxon_set, &finish_args)
+        }
+        Some(Command::FromMatrix { matrix, counts, finish: finish_args }) => {
+            let matrix = LabeledMatrix::from_file(&matrix)?;
+            let counts = match counts {
+                Some(path) => Some(matrix.align(&LabeledMatrix::from_file(&path)?)?),
+                None => matrix.mask.clone(),
+            };
+            info!("read a distance matrix of {} taxa", matrix.names.len());
+            if matrix.names.len() < 2 {
+                anyhow::bail!("fewer than two taxa in the distance matrix");
+            }
+            let mut taxon_set = TaxonSet::new();
+            for name in &matrix.names {
+                taxon_set.request(name.clone());
+            }
+            let mut ustar = UstarState::from_distances(&matrix.dm, counts.as_ref());
+            finish(&mut ustar, &mut taxon_set, &finish_args)
+        }
+    }
+}
+
+/// Reads the gene trees into a distance matrix that is yet to be flattened,
+/// along with the settings they were read with and the number of gene trees
+fn accumulate(args: &Args) -> anyhow::Result<(TreeCollection, UstarState, StateInfo)> {
+    let mut config = args_to_config(args);
     if let Some(preset) = args.preset {
+        apply_preset(&mut config, preset);
         info!(?preset, "applied preset");
     }
-    
+
     info!(
         "analysis started with mode {:?} using {} thread(s)",
         config.mode, args.threads
     );
-    if config.mode == Mode::Support {
-        info!(
-            "support normalization scheme: linearly from [{}, {}] to [0, 1]",
-            config.lower_bound, config.upper_bound
-        );
-    }
-    let mut trees = TreeCollection::from_newick(args.input, &config).unwrap();
-    info!(
-        "read {} gene trees with {} taxa",
-        trees.ngenes(),
-        trees.ntaxa()
-    );
-    let mut ustar = if args.threads == 1 {
-        UstarState::from_tree_collection(&trees, &config)
-    } else {
+    if args.threads > 1 {
         rayon::ThreadPoolBuilder::new()
             .num_threads(args.threads)
             .build_global()
             .unwrap();
-        UstarState::from_tree_collection_par(&trees, &config, args.threads)
-    };
-    ustar.flatten();
-    info!("finished distance matrix calculation");
+    }
+    let mut inputs = args.input.clone();
+    // bounds given on the command line take precedence over inferred ones
+    let auto_bounds = args.auto_bounds && args.bounds.is_none() && args.preset.is_none();
+    if args.auto_bounds && !auto_bounds {
+        warn!("--auto-bounds is ignored, as the bounds are set by -b or --preset");
+    }
+    if args.min_occupancy.is_some() || auto_bounds {
+        // a first pass to count the taxa of the whole dataset and to look at the support
+        let mut all = new_collection(args)?;
+        for spec in &mut inputs {
+            if spec.path == Path::new("-") {
+                anyhow::bail!(
+                    "--min-occupancy and --auto-bounds read the input twice and cannot be used with stdin"
+                );
+            }
+            let file_config = UstarConfig {
+                min_taxa: 0,
+                ..spec.config(&config)
+            };
+            all.stream_file(&spec.path, &file_config, args.batch_size, |_, _| {})?;
+            let stats = all.take_support_stats();
+            // bounds given for the file itself take precedence
+            if auto_bounds && spec.bounds.is_none() && spec.preset.is_none() {
+                match stats.infer_bounds() {
+                    Some((bounds, reason)) => {
+                        info!(
+                            "{}: {}, using bounds {}-{}",
+                            spec.path.display(),
+                            reason,
+                            bounds.0,
+                            bounds.1
+                        );
+                        spec.bounds = Some(bounds);
+                    }
+                    None => info!(
+                        "{}: no support values found, keeping bounds {}-{}",
+                        spec.path.display(),
+                        file_config.lower_bound,
+                        file_config.upper_bound
+                    ),
+                }
+            }
+        }
+        if let Some(occupancy) = args.min_occupancy {
+            let min_taxa = (occupancy * all.ntaxa() as f64).ceil() as usize;
+            info!(
+                "minimum occupancy {} of {} taxa, i.e., {} taxa per gene tree",
+                occupancy,
+                all.ntaxa(),
+                min_taxa
+            );
+            config.min_taxa = config.min_taxa.max(min_taxa);
+        }
+    }
+    // gene trees are parsed and accumulated batch by batch, never all held in memory
+    let mut trees = new_collection(args)?;
+    let mut ustar = UstarState::from_taxon_set(&trees.taxon_set, &config);
+    let mut ngenes = 0usize;
+    let mut accumulator = (args.threads > 1).then(|| ParallelAccumulator::new(args.threads));
+    let mut file_configs = Vec::with_capacity(inputs.len());
+    for spec in &inputs {
+        let file_config = spec.config(&config);
+        let start = ngenes;
+        trees.stream_file(
+            &spec.path,
+            &file_config,
+            args.batch_size,
+            |taxon_set, batch| {
+                ustar.grow(taxon_set.len());
+                match &mut accumulator {
+                    Some(accumulator) => accumulator.add_trees(taxon_set, batch, &file_config),
+                    None => ustar.add_trees(batch, &file_config),
+                }
+                ngenes += batch.len();
+            },
+        )?;
+        info!(
+            "read {} gene trees from {} in mode {:?}",
+            ngenes - start,
+            spec.path.display(),
+            file_config.mode
+        );
+        let stats = trees.take_support_stats();
+        if stats.below + stats.above > 0 {
+            warn!(
+                "{} of {} support values in {} are outside the bounds [{}, {}] (observed [{}, {}]), \
+                 set the right bounds with -b or use --auto-bounds",
+                stats.below + stats.above,
+                stats.count,
+                spec.path.display(),
+                file_config.lower_bound,
+                file_config.upper_bound,
+                stats.min,
+                stats.max
+            );
+        }
+        if matches!(file_config.mode, Mode::Support | Mode::Hybrid) {
+            info!(
+                "support normalization scheme: linearly from [{}, {}] to [0, 1], then {}",
+                file_config.lower_bound, file_config.upper_bound, file_config.support_transform
+            );
+        }
+        file_configs.push(file_config);
+    }
+    if let Some(accumulator) = accumulator {
+        accumulator.finish(&mut ustar);
+    }
+    if trees.nfiltered() > 0 {
+        info!(
+            "dropped {} gene trees with fewer than {} taxa",
+            trees.nfiltered(),
+            config.min_taxa
+        );
+    }
+    info!("read {} gene trees with {} taxa", ngenes, trees.ntaxa());
+    if trees.ntaxa() < 2 {
+        anyhow::bail!("fewer than two taxa left in the gene trees");
+    }
+    if !ustar.scales.is_empty() {
+        let mut sorted = ustar.scales.clone();
+        sorted.sort_unstable_by(f64::total_cmp);
+        info!(
+            "gene tree length scales ({:?}): min {}, median {}, max {}",
+            config.length_normalization,
+            sorted[0],
+            sorted[sorted.len() / 2],
+            sorted[sorted.len() - 1]
+        );
+        if let Some(out) = &args.length_scales {
+            let mut writer = File::create(out)?;
+            for (i, scale) in ustar.scales.iter().enumerate() {
+                writeln!(writer, "{}\t{}", i + 1, scale)?;
+            }
+        }
+    }
+    let info = StateInfo::from_files(&config, &file_configs, ngenes);
+    Ok((trees, ustar, info))
+}
+
+/// Builds a tree from the flattened distance matrix by `--tree-method`;
+/// `use_spr` only applies to FastME
+fn build_tree(ustar: &UstarState, taxon_set: &TaxonSet, args: &FinishArgs, use_spr: bool) -> Tree {
+    let fastme = |method| run_fastme(&ustar.dm, &FastMEConfig::new(method, args.nni, use_spr));
+    match args.tree_method {
+        TreeMethod::Bal => fastme(FastMEMethod::Bal),
+        TreeMethod::Ols => fastme(FastMEMethod::Ols),
+        TreeMethod::Nj => fastme(FastMEMethod::Nj),
+        TreeMethod::Bionj => fastme(FastMEMethod::Bionj),
+        TreeMethod::Unj => fastme(FastMEMethod::Unj),
+        TreeMethod::Rapidnj => run_rapidnj(taxon_set, &ustar.dm, &args.rapidnj_config()),
+        TreeMethod::NativeNj => neighbor_joining(&ustar.dm),
+        TreeMethod::NativeBionj => bionj(&ustar.dm),
+    }
+}
+
+/// Flattens the accumulated distance matrix and outputs it or the species tree
+fn finish(
+    ustar: &mut UstarState,
+    taxon_set: &mut TaxonSet,
+    args: &FinishArgs,
+) -> anyhow::Result<()> {
+    if !ustar.minted {
+        ustar.flatten();
+        info!("finished distance matrix calculation");
+    }
+    if let Some(out) = &args.within_species {
+        let mut writer = File::create(out)?;
+        for (name, within) in taxon_set.names.iter().zip(&ustar.within) {
+            if let Some(d) = within {
+                writeln!(writer, "{}\t{}", name, d)?;
+            }
+        }
+    }
     if args.only_distances {
-        if let Some(out) = args.output {
-            ustar.dm.write_npy(File::create(out)?)?;
+        let format = args.distances_format;
+        if let Some(out) = &args.output {
+            let writer = BufWriter::new(File::create(out)?);
+            write_matrix(writer, format, &taxon_set.names, &ustar.dm, &ustar.mask)?;
         } else {
             // write to stdout
-            ustar.dm.write_npy(std::io::stdout())?;
+            let writer = std::io::stdout().lock();
+            write_matrix(writer, format, &taxon_set.names, &ustar.dm, &ustar.mask)?;
         }
         return Ok(()); // early return
     }
-    let tree: String = if ustar.has_missing {
-        info!(
-            "found missing data, imputing missing distances in mode \"{}\"",
-            args.impute
-        );
-        assert_eq!(args.impute, "uns");
+    if let (TreeMethod::Rapidnj, Some(dir)) = (args.tree_method, &args.rapidnj_cache) {
+        if !dir.is_dir() {
+            anyhow::bail!("rapidNJ cache directory {} does not exist", dir.display());
+        }
+    }
+    let tree: String = if ustar.has_missing && args.impute == Imputation::None {
+        info!("found missing data, building the species tree by BioNJ* without imputation");
+        if args.tree_method != TreeMethod::Bal || args.nni != NniMethod::Bal || args.spr || args.no_spr {
+            warn!("--tree-method, --nni and --spr/--no-spr are ignored by BioNJ* with --impute none");
+        }
+        bionj_star(&ustar.dm, &ustar.mask).newick(taxon_set)
+    } else if ustar.has_missing {
+        info!("found missing data, imputing missing distances in mode \"uns\"");
         let upgma_tree = upgma_star(&ustar.dm, &ustar.mask)?;
-        let impute_mode = if args.length_impute {
-            Mode::NLength
+        let impute_weight = if args.length_impute {
+            ImputeWeight::Length
         } else {
-            Mode::Internode
+            ImputeWeight::Internode
         };
-        impute_matrix(&mut ustar, &upgma_tree, impute_mode);
-        let fastme_nni_tree_str =
-            run_fastme(&trees.taxon_set, &ustar.dm, &FastMEConfig::new(true, false));
-        let fastme_nni_tree = parse_newick(&mut trees.taxon_set, &fastme_nni_tree_str, &config);
-        impute_matrix(&mut ustar, &fastme_nni_tree, impute_mode);
-        ustar.raw_tree(&trees.taxon_set)
+        impute_matrix(ustar, &upgma_tree, impute_weight);
+        let fastme_nni_tree = build_tree(ustar, taxon_set, args, false);
+        impute_matrix(ustar, &fastme_nni_tree, impute_weight);
+        build_tree(ustar, taxon_set, args, !args.no_spr).newick(taxon_set)
     } else {
-        ustar.raw_tree(&trees.taxon_set)
+        build_tree(ustar, taxon_set, args, !args.no_spr).newick(taxon_set)
     };
-    if let Some(out) = args.output {
+    if let Some(out) = &args.output {
         fs::write(out, tree)?;
     } else {
         println!("{}", tree);
@@ -0,0 +1,404 @@
+//! Distance matrices labeled with the taxon names, written by `--only-distances`
+//! and read by `wastrid from-matrix`
+use crate::tree::{open_input, InputError};
+use clap::ArgEnum;
+use ndarray::prelude::*;
+use ndarray_npy::{ReadNpyExt, WriteNpyExt};
+use std::collections::HashSet;
+use std::io::{self, Cursor, Read, Write};
+use std::path::Path;
+use zip::write::FileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+/// Written for the missing pairs in the text formats
+pub const MISSING: &str = "NA";
+
+#[derive(Copy, Clone, PartialEq, Eq, ArgEnum, Debug)]
+pub enum MatrixFormat {
+    /// the raw matrix without names, missing pairs being 0
+    Npy,
+    /// `dm`, `mask` and `names` arrays, missing pairs being NaN in `dm` and 0 in `mask`
+    Npz,
+    /// square PHYLIP
+    Phylip,
+    /// lower-triangular PHYLIP
+    PhylipLower,
+    /// square matrix with a header of names
+    Csv,
+    Tsv,
+    /// one `taxon1,taxon2,mean,count` line per pair
+    Long,
+}
+
+/// The distance and the summed weight of the trees covering the pair `(i, j)`
+/// of a flattened matrix, whose values are above the diagonal
+fn entry(dm: &Array<f64, Ix2>, mask: &Array<f64, Ix2>, i: usize, j: usize) -> (f64, f64) {
+    if i == j {
+        return (0.0, mask[[i, i]]);
+    }
+    let (l, r) = (i.min(j), i.max(j));
+    (dm[[l, r]], mask[[l, r]])
+}
+
+fn format_distance(dm: &Array<f64, Ix2>, mask: &Array<f64, Ix2>, i: usize, j: usize) -> String {
+    match entry(dm, mask, i, j) {
+        (_, m) if i != j && m <= 0.0 => MISSING.to_string(),
+        (d, _) => d.to_string(),
+    }
+}
+
+/// Quotes a CSV field if needed
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+pub fn write_matrix<W: Write>(
+    mut writer: W,
+    format: MatrixFormat,
+    names: &[String],
+    dm: &Array<f64, Ix2>,
+    mask: &Array<f64, Ix2>,
+) -> io::Result<()> {
+    let n = names.len();
+    match format {
+        MatrixFormat::Npy => dm.write_npy(&mut writer).map_err(io::Error::other)?,
+        MatrixFormat::Npz => {
+            let mut full = Array::<f64, _>::zeros((n, n));
+            let mut counts = Array::<f64, _>::zeros((n, n));
+            for i in 0..n {
+                for j in 0..n {
+                    let (d, m) = entry(dm, mask, i, j);
+                    full[[i, j]] = if i != j && m <= 0.0 { f64::NAN } else { d };
+                    counts[[i, j]] = m;
+                }
+            }
+            // the zip archive has to be seekable, unlike stdout
+            let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+            let options = FileOptions::default();
+            zip.start_file("dm.npy", options)?;
+            full.write_npy(&mut zip).map_err(io::Error::other)?;
+            zip.start_file("mask.npy", options)?;
+            counts.write_npy(&mut zip).map_err(io::Error::other)?;
+            zip.start_file("names.npy", options)?;
+            write_npy_strings(&mut zip, names)?;
+            writer.write_all(zip.finish()?.get_ref())?;
+        }
+        MatrixFormat::Phylip | MatrixFormat::PhylipLower => {
+            // names are read back up to the first whitespace
+            if let Some(name) = names.iter().find(|it| it.contains(char::is_whitespace)) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "taxon name `{}` contains whitespace, which PHYLIP cannot hold; use csv, tsv or npz instead",
+                        name
+                    ),
+                ));
+            }
+            writeln!(writer, "{}", n)?;
+            for (i, name) in names.iter().enumerate() {
+                write!(writer, "{}", name)?;
+                let columns = match format {
+                    MatrixFormat::Phylip => n,
+                    _ => i,
+                };
+                for j in 0..columns {
+                    write!(writer, " {}", format_distance(dm, mask, i, j))?;
+                }
+                writeln!(writer)?;
+            }
+        }
+        MatrixFormat::Csv | MatrixFormat::Tsv => {
+            let (sep, quote): (&str, fn(&str) -> String) = match format {
+                MatrixFormat::Csv => (",", csv_field),
+                _ => ("\t", str::to_string),
+            };
+            for name in names {
+                write!(writer, "{}{}", sep, quote(name))?;
+            }
+            writeln!(writer)?;
+            for (i, name) in names.iter().enumerate() {
+                write!(writer, "{}", quote(name))?;
+                for j in 0..n {
+                    write!(writer, "{}{}", sep, format_distance(dm, mask, i, j))?;
+                }
+                writeln!(writer)?;
+            }
+        }
+        MatrixFormat::Long => {
+            writeln!(writer, "taxon1,taxon2,mean,count")?;
+            for i in 0..n {
+                for j in (i + 1)..n {
+                    writeln!(
+                        writer,
+                        "{},{},{},{}",
+                        csv_field(&names[i]),
+                        csv_field(&names[j]),
+                        format_distance(dm, mask, i, j),
+                        entry(dm, mask, i, j).1
+                    )?;
+                }
+            }
+        }
+    }
+    writer.flush()
+}
+
+/// Writes the strings as a NumPy unicode array, which `ndarray-npy` cannot do
+fn write_npy_strings<W: Write>(mut writer: W, strings: &[String]) -> io::Result<()> {
+    let width = strings
+        .iter()
+        .map(|it| it.chars().count())
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let mut header = format!(
+        "{{'descr': '<U{}', 'fortran_order': False, 'shape': ({},), }}",
+        width,
+        strings.len()
+    );
+    // the magic string, version and header length take 10 bytes, and the data
+    // starts at a multiple of 64 bytes
+    let padding = 63 - (10 + header.len()) % 64;
+    header.push_str(&" ".repeat(padding));
+    header.push('\n');
+    writer.write_all(b"\x93NUMPY\x01\x00")?;
+    writer.write_all(&(header.len() as u16).to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+    for s in strings {
+        let mut n = 0;
+        for c in s.chars() {
+            writer.write_all(&(c as u32).to_le_bytes())?;
+            n += 1;
+        }
+        for _ in n..width {
+            writer.write_all(&0u32.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// A symmetric distance matrix read from a file, missing pairs being NaN
+#[derive(Debug, Clone)]
+pub struct LabeledMatrix {
+    pub names: Vec<String>,
+    pub dm: Array<f64, Ix2>,
+    /// the count of each pair, if given along with the distances
+    pub mask: Option<Array<f64, Ix2>>,
+}
+
+impl LabeledMatrix {
+    /// Reads a PHYLIP matrix, square or lower-triangular, or a `.npz` archive
+    /// with `dm`, `names` and optionally `mask` arrays, as written by
+    /// `--only-distances`
+    pub fn from_file(path: &Path) -> Result<Self, InputError> {
+        read_matrix(path).map_err(|source| InputError::io(path, source))
+    }
+
+    /// The distances of `other` rearranged into our order of the taxa
+    pub fn align(&self, other: &LabeledMatrix) -> io::Result<Array<f64, Ix2>> {
+        let n = self.names.len();
+        let mut aligned = Array::<f64, _>::zeros((n, n));
+        let index: Vec<usize> =
+            self.names
+                .iter()
+                .map(|name| {
+                    other.names.iter().position(|it| it == name).ok_or_else(|| {
+                        invalid_data(format!("`{}` is missing from the matrix", name))
+                    })
+                })
+                .collect::<io::Result<_>>()?;
+        for i in 0..n {
+            for j in 0..n {
+                aligned[[i, j]] = other.dm[[index[i], index[j]]];
+            }
+        }
+        Ok(aligned)
+    }
+}
+
+fn read_matrix(path: &Path) -> io::Result<LabeledMatrix> {
+    let mut bytes = Vec::new();
+    open_input(path)?.read_to_end(&mut bytes)?;
+    let matrix = if bytes.starts_with(b"PK\x03\x04") {
+        read_npz(bytes)?
+    } else {
+        let text = String::from_utf8(bytes).map_err(invalid_data)?;
+        read_phylip(&text)?
+    };
+    let mut seen = HashSet::new();
+    if let Some(name) = matrix.names.iter().find(|it| !seen.insert(*it)) {
+        return Err(invalid_data(format!("duplicate taxon `{}`", name)));
+    }
+    Ok(matrix)
+}
+
+fn read_phylip(text: &str) -> io::Result<LabeledMatrix> {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty());
+    let n: usize = lines
+        .next()
+        .and_then(|(_, line)| line.trim().parse().ok())
+        .ok_or_else(|| invalid_data("expected the number of taxa on the first line"))?;
+    let mut names = Vec::with_capacity(n);
+    let mut dm = Array::<f64, _>::from_elem((n, n), f64::NAN);
+    for i in 0..n {
+        let (k, line) = lines
+            .next()
+            .ok_or_else(|| invalid_data(format!("expected {} rows", n)))?;
+        let mut fields = line.split_whitespace();
+        names.push(fields.next().unwrap().to_string());
+        let values = fields
+            .map(|it| match it {
+                _ if it.eq_ignore_ascii_case(MISSING) => Ok(f64::NAN),
+                _ => it.parse::<f64>().map_err(|_| {
+                    invalid_data(format!("line {}: invalid distance `{}`", k + 1, it))
+                }),
+            })
+            .collect::<io::Result<Vec<f64>>>()?;
+        // a square matrix has all the columns, a lower-triangular one those before the diagonal
+        if values.len() != n && values.len() != i {
+            return Err(invalid_data(format!(
+                "line {}: expected {} or {} distances, found {}",
+                k + 1,
+                i,
+                n,
+                values.len()
+            )));
+        }
+        for (j, d) in values.into_iter().enumerate().filter(|&(j, _)| j != i) {
+            // the two halves of a square matrix are averaged
+            dm[[i, j]] = match dm[[i, j]] {
+                prev if prev.is_nan() => d,
+                _ if d.is_nan() => dm[[i, j]],
+                prev => (prev + d) / 2.0,
+            };
+            dm[[j, i]] = dm[[i, j]];
+        }
+        dm[[i, i]] = 0.0;
+    }
+    Ok(LabeledMatrix {
+        names,
+        dm,
+        mask: None,
+    })
+}
+
+fn read_npz(bytes: Vec<u8>) -> io::Result<LabeledMatrix> {
+    let mut zip = ZipArchive::new(Cursor::new(bytes))?;
+    let dm = Array::<f64, Ix2>::read_npy(zip.by_name("dm.npy")?).map_err(io::Error::other)?;
+    let mask = match zip.by_name("mask.npy") {
+        Ok(file) => Some(Array::<f64, Ix2>::read_npy(file).map_err(io::Error::other)?),
+        Err(zip::result::ZipError::FileNotFound) => None,
+        Err(e) => return Err(e.into()),
+    };
+    let mut names = Vec::new();
+    zip.by_name("names.npy")?.read_to_end(&mut names)?;
+    let names = read_npy_strings(&names)?;
+    let n = names.len();
+    if dm.dim() != (n, n) || mask.as_ref().is_some_and(|it| it.dim() != (n, n)) {
+        return Err(invalid_data(format!(
+            "expected {0}x{0} matrices for {0} names",
+            n
+        )));
+    }
+    Ok(LabeledMatrix { names, dm, mask })
+}
+
+/// Reads a NumPy array of unicode (`<U`) or byte (`|S`) strings
+fn read_npy_strings(bytes: &[u8]) -> io::Result<Vec<String>> {
+    let invalid = || invalid_data("expected `names` to be an array of strings");
+    if !bytes.starts_with(b"\x93NUMPY") || bytes.len() < 10 {
+        return Err(invalid());
+    }
+    let (start, len) = match bytes[6] {
+        1 => (10, u16::from_le_bytes([bytes[8], bytes[9]]) as usize),
+        _ if bytes.len() >= 12 => (
+            12,
+            u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize,
+        ),
+        _ => return Err(invalid()),
+    };
+    let header = bytes
+        .get(start..start + len)
+        .and_then(|it| std::str::from_utf8(it).ok())
+        .ok_or_else(invalid)?;
+    let field = |key: &str| {
+        let rest = &header[header.find(key)? + key.len()..];
+        let rest = rest.trim_start_matches([':', ' ', '\'', '(']);
+        Some(&rest[..rest.find(['\'', ',', ')'])?])
+    };
+    let descr = field("'descr'").ok_or_else(invalid)?;
+    let count: usize = field("'shape'")
+        .and_then(|it| it.trim().parse().ok())
+        .ok_or_else(invalid)?;
+    let (unicode, width) = match descr.split_at(2) {
+        ("<U", w) => (true, w.parse::<usize>().map_err(|_| invalid())?),
+        ("|S", w) => (false, w.parse::<usize>().map_err(|_| invalid())?),
+        _ => return Err(invalid()),
+    };
+    let data = &bytes[start + len..];
+    let size = if unicode { 4 * width } else { width };
+    if data.len() < count * size {
+        return Err(invalid());
+    }
+    (0..count)
+        .map(|i| {
+            let item = &data[i * size..(i + 1) * size];
+            let name: String = if unicode {
+                item.chunks(4)
+                    .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+                    .take_while(|&c| c != 0)
+                    .map(|c| char::from_u32(c).ok_or_else(invalid))
+                    .collect::<io::Result<_>>()?
+            } else {
+                let end = item.iter().position(|&b| b == 0).unwrap_or(width);
+                String::from_utf8(item[..end].to_vec()).map_err(|_| invalid())?
+            };
+            Ok(name)
+        })
+        .collect()
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matrix() -> (Vec<String>, Array<f64, Ix2>, Array<f64, Ix2>) {
+        let names = vec!["A".to_string(), "B".to_string(), "C".to_string()];
+        let dm = ndarray::arr2(&[[0.0, 1.5, 2.0], [0.0, 0.0, 3.0], [0.0, 0.0, 0.0]]);
+        let mask = ndarray::arr2(&[[1.0, 2.0, 0.0], [0.0, 1.0, 1.0], [0.0, 0.0, 1.0]]);
+        (names, dm, mask)
+    }
+
+    #[test]
+    fn phylip_round_trip() {
+        let (names, dm, mask) = matrix();
+        for format in [MatrixFormat::Phylip, MatrixFormat::PhylipLower] {
+            let mut out = Vec::new();
+            write_matrix(&mut out, format, &names, &dm, &mask).unwrap();
+            let read = read_phylip(std::str::from_utf8(&out).unwrap()).unwrap();
+            assert_eq!(read.names, names);
+            assert_eq!(read.dm[[0, 1]], 1.5);
+            assert_eq!(read.dm[[2, 1]], 3.0);
+            assert!(read.dm[[0, 2]].is_nan());
+        }
+    }
+
+    #[test]
+    fn phylip_rejects_names_with_whitespace() {
+        let (mut names, dm, mask) = matrix();
+        names[1] = "Homo sapiens".to_string();
+        let err = write_matrix(Vec::new(), MatrixFormat::Phylip, &names, &dm, &mask).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
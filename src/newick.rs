@@ -0,0 +1,312 @@
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use std::iter::{Enumerate, Peekable};
+use std::str::Chars;
+
+/// A lexical unit of a Newick string
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Open,
+    Close,
+    Comma,
+    Colon,
+    Semicolon,
+    /// a quoted or unquoted label, with quotes and escapes already resolved
+    Label(String),
+    /// the text inside a `[...]` comment, without the brackets
+    Comment(String),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Open => write!(f, "("),
+            Token::Close => write!(f, ")"),
+            Token::Comma => write!(f, ","),
+            Token::Colon => write!(f, ":"),
+            Token::Semicolon => write!(f, ";"),
+            Token::Label(label) => write!(f, "{}", label),
+            Token::Comment(comment) => write!(f, "[{}]", comment),
+        }
+    }
+}
+
+/// A syntax error at a specific position of a single Newick string
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewickError {
+    /// 1-based character offset into the Newick string
+    pub column: usize,
+    pub expected: &'static str,
+    /// the offending token, `None` if the input ended prematurely
+    pub found: Option<String>,
+}
+
+impl NewickError {
+    pub(crate) fn new<T: ToString>(pos: usize, expected: &'static str, found: Option<T>) -> Self {
+        NewickError {
+            column: pos + 1,
+            expected,
+            found: found.map(|it| it.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for NewickError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.found {
+            Some(found) => write!(
+                f,
+                "column {}: expected {}, found `{}`",
+                self.column, self.expected, found
+            ),
+            None => write!(
+                f,
+                "column {}: expected {}, found end of input",
+                self.column, self.expected
+            ),
+        }
+    }
+}
+
+impl Error for NewickError {}
+
+/// Splits a Newick string into tokens, each paired with its 0-based character offset.
+///
+/// Whitespace between tokens is ignored. Unquoted labels run until the next
+/// delimiter and are trimmed; single- or double-quoted labels may contain any
+/// character, with a doubled quote standing for a literal one.
+pub struct Lexer<'a> {
+    chars: Peekable<Enumerate<Chars<'a>>>,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(newick: &'a str) -> Self {
+        Lexer {
+            chars: newick.chars().enumerate().peekable(),
+        }
+    }
+
+    fn quoted(&mut self, pos: usize, quote: char) -> Result<Token, NewickError> {
+        let mut label = String::new();
+        loop {
+            match self.chars.next() {
+                Some((_, c)) if c == quote => {
+                    if matches!(self.chars.peek(), Some((_, c)) if *c == quote) {
+                        self.chars.next();
+                        label.push(quote);
+                    } else {
+                        return Ok(Token::Label(label));
+                    }
+                }
+                Some((_, c)) => label.push(c),
+                None => {
+                    return Err(NewickError::new(
+                        pos,
+                        "a closing quote",
+                        Some(format!("{}{}", quote, label)),
+                    ))
+                }
+            }
+        }
+    }
+
+    fn comment(&mut self, pos: usize) -> Result<Token, NewickError> {
+        let mut comment = String::new();
+        let mut depth = 0usize;
+        loop {
+            match self.chars.next() {
+                Some((_, ']')) if depth == 0 => return Ok(Token::Comment(comment)),
+                Some((_, c)) => {
+                    match c {
+                        '[' => depth += 1,
+                        ']' => depth -= 1,
+                        _ => {}
+                    }
+                    comment.push(c);
+                }
+                None => return Err(NewickError::new(pos, "']'", Some(format!("[{}", comment)))),
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<(usize, Token), NewickError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((pos, c)) = self.chars.next() {
+            let token = match c {
+                '(' => Ok(Token::Open),
+                ')' => Ok(Token::Close),
+                ',' => Ok(Token::Comma),
+                ':' => Ok(Token::Colon),
+                ';' => Ok(Token::Semicolon),
+                '[' => self.comment(pos),
+                '\'' | '"' => self.quoted(pos, c),
+                c if c.is_whitespace() => continue,
+                c => {
+                    let mut label = c.to_string();
+                    while let Some((_, c)) = self.chars.peek() {
+                        if is_delimiter(*c) {
+                            break;
+                        }
+                        label.push(*c);
+                        self.chars.next();
+                    }
+                    Ok(Token::Label(label.trim_end().to_string()))
+                }
+            };
+            return Some(token.map(|t| (pos, t)));
+        }
+        None
+    }
+}
+
+fn is_delimiter(c: char) -> bool {
+    matches!(c, '(' | ')' | ',' | ':' | ';' | '[' | ']' | '\'' | '"')
+}
+
+/// Quotes a label for output if it would not survive a round trip unquoted
+pub fn quote_label(label: &str) -> Cow<'_, str> {
+    if !label.is_empty() && !label.chars().any(|c| is_delimiter(c) || c.is_whitespace()) {
+        Cow::Borrowed(label)
+    } else {
+        Cow::Owned(format!("'{}'", label.replace('\'', "''")))
+    }
+}
+
+/// Extracts key/value pairs from an annotation comment.
+///
+/// Understands NHX (`&&NHX:k1=v1:k2=v2`) and the BEAST/IQ-TREE style
+/// (`&k1=v1,k2={a,b}` or `&W 0.5`); other comments carry no attributes.
+pub fn parse_attributes(comment: &str) -> Vec<(String, String)> {
+    if let Some(nhx) = comment.strip_prefix("&&NHX") {
+        nhx.split(':')
+            .filter(|it| !it.is_empty())
+            .map(|it| split_attribute(it, '='))
+            .collect()
+    } else if let Some(rest) = comment.strip_prefix('&') {
+        let mut items = Vec::new();
+        let mut depth = 0usize;
+        let mut quoted = false;
+        let mut start = 0usize;
+        for (i, c) in rest.char_indices() {
+            match c {
+                '"' => quoted = !quoted,
+                '{' if !quoted => depth += 1,
+                '}' if !quoted => depth = depth.saturating_sub(1),
+                ',' if !quoted && depth == 0 => {
+                    items.push(&rest[start..i]);
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        items.push(&rest[start..]);
+        items
+            .into_iter()
+            .map(str::trim)
+            .filter(|it| !it.is_empty())
+            .map(|it| {
+                if it.contains('=') {
+                    split_attribute(it, '=')
+                } else {
+                    split_attribute(it, ' ')
+                }
+            })
+            .collect()
+    } else {
+        Vec::new()
+    }
+}
+
+fn split_attribute(item: &str, sep: char) -> (String, String) {
+    match item.split_once(sep) {
+        Some((k, v)) => (k.trim().to_string(), v.trim().to_string()),
+        None => (item.trim().to_string(), String::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(newick: &str) -> Vec<Token> {
+        Lexer::new(newick).map(|it| it.unwrap().1).collect()
+    }
+
+    fn label(label: &str) -> Token {
+        Token::Label(label.to_string())
+    }
+
+    #[test]
+    fn quoted_labels() {
+        assert_eq!(
+            tokens("('it''s a'  , \"say \"\"hi\"\"\",b c):1;"),
+            vec![
+                Token::Open,
+                label("it's a"),
+                Token::Comma,
+                label("say \"hi\""),
+                Token::Comma,
+                label("b c"),
+                Token::Close,
+                Token::Colon,
+                label("1"),
+                Token::Semicolon,
+            ]
+        );
+        let error = Lexer::new("(a,'b").find_map(Result::err).unwrap();
+        assert_eq!(error.column, 4);
+        assert_eq!(error.found.as_deref(), Some("'b"));
+    }
+
+    #[test]
+    fn nested_comments() {
+        assert_eq!(
+            tokens("(a[x[y]z],b)[&W 2];"),
+            vec![
+                Token::Open,
+                label("a"),
+                Token::Comment("x[y]z".to_string()),
+                Token::Comma,
+                label("b"),
+                Token::Close,
+                Token::Comment("&W 2".to_string()),
+                Token::Semicolon,
+            ]
+        );
+        let error = Lexer::new("(a[x[y],b);").find_map(Result::err).unwrap();
+        assert_eq!(error.column, 3);
+        assert_eq!(error.expected, "']'");
+    }
+
+    #[test]
+    fn attributes() {
+        let pairs = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        assert_eq!(
+            parse_attributes("&&NHX:B=95:S=human"),
+            pairs(&[("B", "95"), ("S", "human")])
+        );
+        assert_eq!(
+            parse_attributes("&support=0.9,range={1,2},name=\"a,b\""),
+            pairs(&[("support", "0.9"), ("range", "{1,2}"), ("name", "\"a,b\"")])
+        );
+        assert_eq!(parse_attributes("&W 0.5"), pairs(&[("W", "0.5")]));
+        assert!(parse_attributes("a plain comment").is_empty());
+    }
+
+    #[test]
+    fn quote_only_when_needed() {
+        assert_eq!(quote_label("Homo_sapiens"), "Homo_sapiens");
+        assert_eq!(quote_label("it's"), "'it''s'");
+        assert_eq!(quote_label("a b"), "'a b'");
+        assert_eq!(quote_label(""), "''");
+    }
+}
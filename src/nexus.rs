@@ -0,0 +1,261 @@
+use crate::newick::{Lexer, Token};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+
+/// A `;`-terminated NEXUS command, e.g. `TREE gene1 = (A,(B,C));`
+#[derive(Debug)]
+pub struct Command {
+    /// 1-based line and column of the first character of `text`
+    pub line: usize,
+    pub column: usize,
+    /// the command without its terminating `;`, line breaks preserved
+    pub text: String,
+}
+
+impl Command {
+    /// The lowercased command name and the byte offset right after it,
+    /// skipping any leading whitespace and `[...]` comments
+    pub fn keyword(&self) -> (String, usize) {
+        let mut depth = 0usize;
+        let mut start = None;
+        for (i, c) in self.text.char_indices() {
+            match (start, c) {
+                (None, '[') => depth += 1,
+                (None, ']') if depth > 0 => depth -= 1,
+                (None, c) if depth > 0 || c.is_whitespace() => {}
+                (None, _) => start = Some(i),
+                (Some(s), c) if !(c.is_alphanumeric() || c == '_') => {
+                    return (self.text[s..i].to_lowercase(), i);
+                }
+                _ => {}
+            }
+        }
+        match start {
+            Some(s) => (self.text[s..].to_lowercase(), self.text.len()),
+            None => (String::new(), self.text.len()),
+        }
+    }
+
+    /// Translates a 1-based character column relative to the byte offset
+    /// `start` of `text` into a 1-based (line, column) of the whole file
+    pub fn locate(&self, start: usize, column: usize) -> (usize, usize) {
+        let pos = self.text[..start].chars().count() + column - 1;
+        let mut line = self.line;
+        let mut col = self.column;
+        for c in self.text.chars().take(pos) {
+            if c == '\n' {
+                line += 1;
+                col = 1;
+            } else {
+                col += 1;
+            }
+        }
+        (line, col)
+    }
+}
+
+/// Splits NEXUS input into commands, honoring quotes and bracket comments
+pub struct Commands<I> {
+    lines: I,
+    text: String,
+    start: Option<(usize, usize)>,
+    quote: Option<char>,
+    depth: usize,
+    pending: VecDeque<Command>,
+}
+
+impl<I> Commands<I>
+where
+    I: Iterator<Item = (usize, io::Result<String>)>,
+{
+    /// `lines` yields the 0-based line number along with each line
+    pub fn new(lines: I) -> Self {
+        Commands {
+            lines,
+            text: String::new(),
+            start: None,
+            quote: None,
+            depth: 0,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn emit(&mut self) {
+        if let Some((line, column)) = self.start.take() {
+            let text = std::mem::take(&mut self.text);
+            if !text.trim().is_empty() {
+                self.pending.push_back(Command {
+                    line: line + 1,
+                    column: column + 1,
+                    text,
+                });
+            }
+        }
+    }
+}
+
+impl<I> Iterator for Commands<I>
+where
+    I: Iterator<Item = (usize, io::Result<String>)>,
+{
+    type Item = io::Result<Command>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(command) = self.pending.pop_front() {
+                return Some(Ok(command));
+            }
+            let (no, line) = match self.lines.next() {
+                Some((no, Ok(line))) => (no, line),
+                Some((_, Err(e))) => return Some(Err(e)),
+                None => {
+                    // tolerate a missing `;` after the last command
+                    self.emit();
+                    return self.pending.pop_front().map(Ok);
+                }
+            };
+            if self.start.is_some() {
+                self.text.push('\n');
+            }
+            for (col, c) in line.chars().enumerate() {
+                if self.start.is_none() {
+                    self.start = Some((no, col));
+                }
+                match (self.quote, c) {
+                    (Some(q), c) if q == c => self.quote = None,
+                    (Some(_), _) => {}
+                    (None, '\'') | (None, '"') if self.depth == 0 => self.quote = Some(c),
+                    (None, '[') => self.depth += 1,
+                    (None, ']') if self.depth > 0 => self.depth -= 1,
+                    (None, ';') if self.depth == 0 => {
+                        self.emit();
+                        continue;
+                    }
+                    _ => {}
+                }
+                self.text.push(c);
+            }
+        }
+    }
+}
+
+/// Whether the first non-blank line marks a NEXUS file
+pub fn is_nexus_header(line: &str) -> bool {
+    matches!(line.trim_start().get(..6), Some(it) if it.eq_ignore_ascii_case("#nexus"))
+}
+
+/// Parses the body of a `TRANSLATE` command into an alias to taxon name map
+pub fn parse_translate(body: &str) -> HashMap<String, String> {
+    let mut table = HashMap::new();
+    let mut quote = None;
+    let mut start = 0usize;
+    let mut entries = Vec::new();
+    for (i, c) in body.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if q == c => quote = None,
+            (Some(_), _) => {}
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, ',') => {
+                entries.push(&body[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    entries.push(&body[start..]);
+    for entry in entries {
+        let entry = entry.trim();
+        if let Some((alias, name)) = entry.split_once(char::is_whitespace) {
+            let name = match Lexer::new(name.trim()).next() {
+                Some(Ok((_, Token::Label(label)))) => label,
+                _ => name.trim().to_string(),
+            };
+            table.insert(alias.to_string(), name);
+        }
+    }
+    table
+}
+
+/// The name of a tree from the part of a `TREE` command before the `=`,
+/// without quotes and without the `*` marking PAUP*'s default tree
+pub fn tree_name(text: &str) -> String {
+    let text = text.trim().trim_start_matches('*').trim();
+    match Lexer::new(text).find(|it| !matches!(it, Ok((_, Token::Comment(_))))) {
+        Some(Ok((_, Token::Label(label)))) => label,
+        _ => text.to_string(),
+    }
+}
+
+/// Finds the Newick part of a `TREE name = ...` command, i.e., the byte offset
+/// right after the first `=` that is not inside quotes or comments
+pub fn tree_body(text: &str, after_keyword: usize) -> Option<usize> {
+    let mut quote = None;
+    let mut depth = 0usize;
+    for (i, c) in text[after_keyword..].char_indices() {
+        match (quote, c) {
+            (Some(q), c) if q == c => quote = None,
+            (Some(_), _) => {}
+            (None, '\'') | (None, '"') if depth == 0 => quote = Some(c),
+            (None, '[') => depth += 1,
+            (None, ']') if depth > 0 => depth -= 1,
+            (None, '=') if depth == 0 => return Some(after_keyword + i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commands(nexus: &str) -> Vec<Command> {
+        Commands::new(nexus.lines().map(|it| Ok(it.to_string())).enumerate())
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn split_commands() {
+        let nexus = "#NEXUS\nbegin trees; [a; comment]\n  tree 'it''s;' = (A,B);\nend";
+        let commands = commands(nexus);
+        let texts: Vec<&str> = commands.iter().map(|it| it.text.trim()).collect();
+        assert_eq!(
+            texts,
+            vec![
+                "#NEXUS\nbegin trees",
+                "[a; comment]\n  tree 'it''s;' = (A,B)",
+                "end"
+            ]
+        );
+        assert_eq!(commands[1].keyword(), ("tree".to_string(), 20));
+        assert_eq!((commands[1].line, commands[1].column), (2, 13));
+        // the `(` of the tree, on the third line
+        let body = tree_body(&commands[1].text, 20).unwrap();
+        assert_eq!(commands[1].locate(body, 2), (3, 19));
+    }
+
+    #[test]
+    fn translate_table() {
+        let table = parse_translate("\n 1 Homo_sapiens,\n 2 'Pan troglodytes', 3 'a,b'\n");
+        assert_eq!(table.len(), 3);
+        assert_eq!(table["1"], "Homo_sapiens");
+        assert_eq!(table["2"], "Pan troglodytes");
+        assert_eq!(table["3"], "a,b");
+    }
+
+    #[test]
+    fn tree_names() {
+        assert_eq!(tree_name(" gene1 "), "gene1");
+        assert_eq!(tree_name("* 'gene 2' [&U]"), "gene 2");
+        let text = "TREE 'a=b' [=] = (A,B)";
+        assert_eq!(&text[tree_body(text, 4).unwrap()..], " (A,B)");
+        assert_eq!(tree_body("TREE gene1", 4), None);
+    }
+
+    #[test]
+    fn nexus_header() {
+        assert!(is_nexus_header("  #nexus"));
+        assert!(!is_nexus_header("(A,B);"));
+    }
+}
@@ -0,0 +1,94 @@
+use crate::tree::{open_input, InputError};
+use regex::Regex;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{self, BufRead};
+use std::path::Path;
+
+/// Maps the leaf labels of the gene trees (individuals) to species
+#[derive(Debug, Clone)]
+pub enum SpeciesMapping {
+    /// an explicit individual to species table
+    Table(HashMap<String, String>),
+    /// the first capture group (or the whole match) of a regex
+    Regex(Regex),
+    /// everything before the last occurrence of a delimiter
+    Delimiter(String),
+}
+
+impl SpeciesMapping {
+    /// Reads a mapping file, either in the ASTRAL `-a` format, one
+    /// `species:individual1,individual2,...` per line, or in the two column
+    /// format, one `individual species` per line
+    pub fn from_file(path: &Path) -> Result<Self, InputError> {
+        read_table(path)
+            .map(SpeciesMapping::Table)
+            .map_err(|source| InputError::io(path, source))
+    }
+
+    /// The species of an individual, `None` if the mapping does not cover it
+    pub fn species<'a>(&self, label: &'a str) -> Option<Cow<'a, str>> {
+        match self {
+            SpeciesMapping::Table(table) => table.get(label).map(|it| Cow::Owned(it.clone())),
+            SpeciesMapping::Regex(re) => {
+                let captures = re.captures(label)?;
+                let m = captures.get(1).or_else(|| captures.get(0))?;
+                Some(Cow::Borrowed(m.as_str()))
+            }
+            SpeciesMapping::Delimiter(delimiter) => label
+                .rsplit_once(delimiter.as_str())
+                .map(|(species, _)| Cow::Borrowed(species)),
+        }
+    }
+}
+
+fn read_table(path: &Path) -> io::Result<HashMap<String, String>> {
+    let mut table = HashMap::new();
+    for (i, line) in open_input(path)?.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let pairs: Vec<(&str, &str)> = if let Some((species, individuals)) = line.split_once(':') {
+            individuals
+                .split(',')
+                .map(str::trim)
+                .filter(|it| !it.is_empty())
+                .map(|it| (it, species.trim()))
+                .collect()
+        } else {
+            match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [individual, species] => vec![(individual, species)],
+                _ => {
+                    return Err(invalid_line(
+                        i,
+                        "expected `species:individual,...` or `individual species`".to_string(),
+                    ))
+                }
+            }
+        };
+        for (individual, species) in pairs {
+            match table.insert(individual.to_string(), species.to_string()) {
+                Some(other) if other != species => {
+                    return Err(invalid_line(
+                        i,
+                        format!(
+                            "`{}` is mapped to both `{}` and `{}`",
+                            individual, other, species
+                        ),
+                    ));
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(table)
+}
+
+fn invalid_line(i: usize, message: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("line {}: {}", i + 1, message),
+    )
+}
@@ -0,0 +1,230 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// Summary of the support values found in the gene trees of an input
+#[derive(Debug, Clone, Default)]
+pub struct SupportStats {
+    /// number of support labels
+    pub count: usize,
+    /// number of labels made of two `/`-separated values, e.g. SH-aLRT/UFBoot
+    pub pairs: usize,
+    pub min: f64,
+    pub max: f64,
+    /// number of values at 1/3 (e.g. `0.333`), the minimum of aBayes support
+    pub thirds: usize,
+    /// number of values below the lower and above the upper bound in use
+    pub below: usize,
+    pub above: usize,
+}
+
+impl SupportStats {
+    /// Records the support value `s` read from a label with `fields` values
+    pub fn record(&mut self, s: f64, fields: usize, lower: f64, upper: f64) {
+        if self.count == 0 {
+            self.min = s;
+            self.max = s;
+        } else {
+            self.min = self.min.min(s);
+            self.max = self.max.max(s);
+        }
+        self.count += 1;
+        if fields == 2 {
+            self.pairs += 1;
+        }
+        if (s - 1.0 / 3.0).abs() < 5e-4 {
+            self.thirds += 1;
+        }
+        if s < lower {
+            self.below += 1;
+        } else if s > upper {
+            self.above += 1;
+        }
+    }
+
+    /// Guesses the scale of the support values, returning the bounds along
+    /// with the reason for picking them, or `None` if there is no support
+    pub fn infer_bounds(&self) -> Option<((f64, f64), String)> {
+        if self.count == 0 {
+            return None;
+        }
+        let range = format!(
+            "{} support values within [{}, {}]",
+            self.count, self.min, self.max
+        );
+        Some(if self.pairs * 2 > self.count {
+            (
+                (0.0, 100.0),
+                format!(
+                    "{} of {} labels are value pairs as in IQ-TREE's SH-aLRT/UFBoot, both in percent",
+                    self.pairs, self.count
+                ),
+            )
+        } else if self.max <= 1.0 && self.min >= 0.333 && self.thirds * 20 >= self.count {
+            (
+                (0.333, 1.0),
+                format!(
+                    "{}, none below 1/3 and {} at 1/3, looks like aBayes",
+                    range, self.thirds
+                ),
+            )
+        } else if self.max <= 1.0 && self.min >= 0.333 {
+            // well supported proportions look the same
+            (
+                (0.0, 1.0),
+                format!(
+                    "{}, none below 1/3 but only {} at 1/3, assuming proportions rather than aBayes (set `:abayes` on the file otherwise)",
+                    range, self.thirds
+                ),
+            )
+        } else if self.max <= 1.0 {
+            ((0.0, 1.0), format!("{}, looks like proportions", range))
+        } else if self.max <= 100.0 {
+            ((0.0, 100.0), format!("{}, looks like percentages", range))
+        } else {
+            // e.g. raw bootstrap counts out of 1000 replicates
+            let upper = 10f64.powf(self.max.log10().ceil());
+            (
+                (0.0, upper),
+                format!("{}, looks like counts out of {}", range, upper),
+            )
+        })
+    }
+}
+
+/// Edge weight as a function of the rescaled support `s` in `Mode::Support`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SupportTransform {
+    /// `s` itself
+    Linear,
+    /// `s^k`
+    Power(f64),
+    /// `1 / (1 + exp(-k (s - mid)))`
+    Logistic { k: f64, mid: f64 },
+    /// 1 if `s` is at least the threshold, 0 otherwise
+    Threshold(f64),
+    /// `-ln(1 - s)`, with `s` capped so that the weight stays finite
+    NegLog { cap: f64 },
+}
+
+impl SupportTransform {
+    pub fn apply(&self, s: f64) -> f64 {
+        match *self {
+            SupportTransform::Linear => s,
+            SupportTransform::Power(k) => s.powf(k),
+            SupportTransform::Logistic { k, mid } => 1.0 / (1.0 + (-k * (s - mid)).exp()),
+            SupportTransform::Threshold(t) => {
+                if s >= t {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            SupportTransform::NegLog { cap } => -(1.0 - s.min(cap)).ln(),
+        }
+    }
+}
+
+impl fmt::Display for SupportTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SupportTransform::Linear => write!(f, "linear"),
+            SupportTransform::Power(k) => write!(f, "power:{}", k),
+            SupportTransform::Logistic { k, mid } => write!(f, "logistic:{}:{}", k, mid),
+            SupportTransform::Threshold(t) => write!(f, "threshold:{}", t),
+            SupportTransform::NegLog { cap } => write!(f, "neglog:{}", cap),
+        }
+    }
+}
+
+impl FromStr for SupportTransform {
+    type Err = String;
+
+    /// `linear`, `power:K`, `logistic[:K[:MID]]`, `threshold:T` or `neglog[:CAP]`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let name = parts.next().unwrap_or_default().to_lowercase();
+        let params = parts
+            .map(|it| {
+                it.parse::<f64>()
+                    .map_err(|_| format!("invalid parameter `{}`", it))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        match (name.as_str(), &params[..]) {
+            ("linear", []) => Ok(SupportTransform::Linear),
+            ("power", &[k]) if k > 0.0 && k.is_finite() => Ok(SupportTransform::Power(k)),
+            ("logistic", []) => Ok(SupportTransform::Logistic { k: 10.0, mid: 0.5 }),
+            ("logistic", &[k]) if k.is_finite() => Ok(SupportTransform::Logistic { k, mid: 0.5 }),
+            ("logistic", &[k, mid]) if k.is_finite() && mid.is_finite() => {
+                Ok(SupportTransform::Logistic { k, mid })
+            }
+            ("threshold", &[t]) if (0.0..=1.0).contains(&t) => Ok(SupportTransform::Threshold(t)),
+            ("neglog", []) => Ok(SupportTransform::NegLog { cap: 0.999 }),
+            ("neglog", &[cap]) if cap < 1.0 => Ok(SupportTransform::NegLog { cap }),
+            _ => Err(format!(
+                "expected linear, power:K, logistic[:K[:MID]], threshold:T or neglog[:CAP], found `{}`",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(values: &[f64]) -> SupportStats {
+        let mut stats = SupportStats::default();
+        for &s in values {
+            stats.record(s, 1, 0.0, 1.0);
+        }
+        stats
+    }
+
+    #[test]
+    fn infers_abayes_from_values_at_one_third() {
+        let (bounds, _) = stats(&[0.333, 0.9, 1.0, 0.5, 0.3333, 1.0])
+            .infer_bounds()
+            .unwrap();
+        assert_eq!(bounds, (0.333, 1.0));
+    }
+
+    #[test]
+    fn high_proportions_are_not_abayes() {
+        let (bounds, reason) = stats(&[0.4, 0.9, 1.0, 0.75, 0.98]).infer_bounds().unwrap();
+        assert_eq!(bounds, (0.0, 1.0));
+        assert!(reason.contains("rather than aBayes"));
+        let (bounds, _) = stats(&[0.1, 0.9]).infer_bounds().unwrap();
+        assert_eq!(bounds, (0.0, 1.0));
+        let (bounds, _) = stats(&[12.0, 95.0]).infer_bounds().unwrap();
+        assert_eq!(bounds, (0.0, 100.0));
+        assert!(stats(&[]).infer_bounds().is_none());
+    }
+
+    #[test]
+    fn transform_parameters_are_checked() {
+        for s in [
+            "power:2",
+            "logistic:5:0.25",
+            "threshold:0",
+            "threshold:1",
+            "neglog:0.99",
+        ] {
+            let transform = s.parse::<SupportTransform>().unwrap();
+            assert_eq!(transform.to_string(), s);
+        }
+        for s in [
+            "power:0",
+            "power:-1",
+            "power:inf",
+            "power:NaN",
+            "logistic:inf",
+            "logistic:10:NaN",
+            "threshold:-0.1",
+            "threshold:1.5",
+            "threshold:NaN",
+            "neglog:1",
+        ] {
+            assert!(s.parse::<SupportTransform>().is_err(), "{} was accepted", s);
+        }
+    }
+}
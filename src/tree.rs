@@ -1,14 +1,44 @@
+use crate::aggregate::Aggregation;
+use crate::disco::decompose;
+use crate::newick::{parse_attributes, quote_label, Lexer, NewickError, Token};
+use crate::nexus::{is_nexus_header, parse_translate, tree_body, tree_name, Commands};
+use crate::species::SpeciesMapping;
+use crate::support::{SupportStats, SupportTransform};
+use crate::weights::{parse_weight, GeneWeights};
+use bzip2::read::MultiBzDecoder;
 use clap::ArgEnum;
-use std::collections::HashMap;
+use flate2::read::MultiGzDecoder;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
 use std::fs::File;
-use std::io::{self, BufRead};
-use std::path::Path;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use tracing::warn;
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum, Debug)]
 pub enum Mode {
     Support,
     Internode,
     NLength,
+    /// branch length, relative to the mean branch length of the gene tree,
+    /// times the transformed support
+    Hybrid,
+}
+
+/// Per gene tree scale that path lengths are divided by in `Mode::NLength`
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum, Debug)]
+pub enum LengthNormalization {
+    /// raw path lengths
+    None,
+    /// the largest leaf-to-leaf distance
+    Diameter,
+    /// the median leaf-to-leaf distance
+    Median,
+    /// the mean root-to-tip distance after midpoint rooting
+    Midpoint,
+    /// the sum of the branch lengths
+    Total,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
@@ -18,10 +48,40 @@ pub enum ImputeMethod {
     BalMESPR,
 }
 
+#[derive(Clone, Debug)]
 pub struct UstarConfig {
     pub upper_bound: f64,
     pub lower_bound: f64,
     pub mode: Mode,
+    /// edge weight in `Mode::Support` and `Mode::Hybrid` as a function of the
+    /// rescaled support
+    pub support_transform: SupportTransform,
+    /// how gene trees are rescaled in `Mode::NLength`
+    pub length_normalization: LengthNormalization,
+    /// how the distances of a pair across gene trees are summarized
+    pub aggregation: Aggregation,
+    /// keep at most this many distances per pair for the aggregation,
+    /// approximating it in bounded memory
+    pub sketch_size: Option<usize>,
+    pub skip_invalid: bool,
+    /// keep the `[...]` comments on the trees, reading the support of internal
+    /// nodes from their `support` or NHX `B` attribute
+    pub keep_comments: bool,
+    /// which of the `/`-separated values of a support label to use, 0-based,
+    /// the last one if the label has fewer values
+    pub support_field: usize,
+    /// drop gene trees with fewer distinct taxa (after pruning) than this
+    pub min_taxa: usize,
+    /// weight gene trees by their `[&W ...]` annotation
+    pub weight_annotations: bool,
+    /// contract internal branches with support below this value, in the same
+    /// units as the input support
+    pub contract_support: Option<f64>,
+    /// contract internal branches shorter than this
+    pub contract_length: Option<f64>,
+    /// decompose multi-copy gene family trees with DISCO, keeping the
+    /// single-copy trees with at least this many leaves
+    pub disco: Option<usize>,
 }
 
 impl Default for UstarConfig {
@@ -30,24 +90,68 @@ impl Default for UstarConfig {
             upper_bound: 1.0,
             lower_bound: 0.0,
             mode: Mode::Support,
+            support_transform: SupportTransform::Linear,
+            length_normalization: LengthNormalization::Diameter,
+            aggregation: Aggregation::Mean,
+            sketch_size: None,
+            skip_invalid: false,
+            keep_comments: false,
+            support_field: 0,
+            min_taxa: 0,
+            weight_annotations: false,
+            contract_support: None,
+            contract_length: None,
+            disco: None,
         }
     }
 }
 
+/// Consumer of the batches of trees read by `TreeCollection::stream_file`
+type BatchFn<'a> = &'a mut dyn FnMut(&TaxonSet, &[Tree]);
+
 #[derive(Debug)]
 pub struct TreeCollection {
     pub taxon_set: TaxonSet,
     pub trees: Vec<Tree>,
+    /// overrides the weights of the gene trees
+    pub weights: Option<GeneWeights>,
+    /// number of gene trees read so far, including the skipped ones
+    nread: usize,
+    /// number of gene trees dropped for having too few taxa
+    nfiltered: usize,
+    support_stats: SupportStats,
 }
 
 // The output is wrapped in a Result to allow matching on errors
 // Returns an Iterator to the Reader of the lines of the file.
-fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
+fn read_lines<P>(filename: P) -> io::Result<io::Lines<Box<dyn BufRead>>>
 where
     P: AsRef<Path>,
 {
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
+    Ok(open_input(filename.as_ref())?.lines())
+}
+
+/// Opens `path` for reading, `-` meaning stdin. gzip, zstd and bzip2
+/// compressed input is decompressed on the fly, detected by its magic bytes.
+pub fn open_input(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    let mut reader: Box<dyn Read> = if path == Path::new("-") {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(path)?)
+    };
+    // pipes may deliver fewer bytes per read than asked for
+    let mut magic = Vec::with_capacity(4);
+    (&mut reader).take(4).read_to_end(&mut magic)?;
+    let reader = io::Cursor::new(magic.clone()).chain(reader);
+    Ok(if magic.starts_with(&[0x1f, 0x8b]) {
+        Box::new(BufReader::new(MultiGzDecoder::new(reader)))
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Box::new(BufReader::new(zstd::Decoder::new(reader)?))
+    } else if magic.starts_with(b"BZh") {
+        Box::new(BufReader::new(MultiBzDecoder::new(reader)))
+    } else {
+        Box::new(BufReader::new(reader))
+    })
 }
 
 #[derive(Debug)]
@@ -55,10 +159,50 @@ pub struct TaxonSet {
     pub to_id: HashMap<String, usize>,
     pub names: Vec<String>,
     last: usize,
+    /// if set, the taxa are species and leaf labels are individuals mapped to them
+    species: Option<SpeciesMapping>,
+    /// leaves to prune from the gene trees
+    filter: Option<TaxonFilter>,
+}
+
+/// Taxa to keep in or to remove from the gene trees, by leaf label or species
+#[derive(Debug, Clone)]
+pub enum TaxonFilter {
+    Include(HashSet<String>),
+    Exclude(HashSet<String>),
+}
+
+impl TaxonFilter {
+    /// Reads one taxon name per line, skipping blank lines and `#` comments
+    pub fn from_file(path: &Path, include: bool) -> Result<Self, InputError> {
+        let mut names = HashSet::new();
+        for line in open_input(path)
+            .map_err(|source| InputError::io(path, source))?
+            .lines()
+        {
+            let line = line.map_err(|source| InputError::io(path, source))?;
+            let name = line.trim();
+            if !name.is_empty() && !name.starts_with('#') {
+                names.insert(name.to_string());
+            }
+        }
+        Ok(if include {
+            TaxonFilter::Include(names)
+        } else {
+            TaxonFilter::Exclude(names)
+        })
+    }
+
+    fn names(&self) -> &HashSet<String> {
+        match self {
+            TaxonFilter::Include(names) | TaxonFilter::Exclude(names) => names,
+        }
+    }
 }
 
 impl TaxonSet {
     pub fn request(&mut self, taxon_name: String) -> usize {
+        let taxon_name = self.species_of(taxon_name);
         *self.to_id.entry(taxon_name.clone()).or_insert_with(|| {
             self.names.push(taxon_name);
             self.last += 1;
@@ -75,12 +219,73 @@ impl TaxonSet {
             to_id: HashMap::new(),
             names: Vec::new(),
             last: 0,
+            species: None,
+            filter: None,
+        }
+    }
+
+    pub fn set_filter(&mut self, filter: TaxonFilter) {
+        self.filter = Some(filter);
+    }
+
+    /// Whether the leaves labeled `label` are pruned by the filter, either by
+    /// their own label or by the name of their species
+    pub fn is_filtered(&self, label: &str) -> bool {
+        let filter = match &self.filter {
+            Some(filter) => filter,
+            None => return false,
+        };
+        let names = filter.names();
+        let listed = names.contains(label)
+            || self
+                .species
+                .as_ref()
+                .and_then(|it| it.species(label))
+                .is_some_and(|species| names.contains(species.as_ref()));
+        match filter {
+            TaxonFilter::Include(_) => !listed,
+            TaxonFilter::Exclude(_) => listed,
+        }
+    }
+
+    /// A taxon set of species, where each requested label is an individual
+    /// translated to its species through `mapping`
+    pub fn with_species(mapping: SpeciesMapping) -> Self {
+        TaxonSet {
+            species: Some(mapping),
+            ..TaxonSet::new()
+        }
+    }
+
+    /// Labels that are already species names (e.g., in trees inferred on the
+    /// species) are kept as is, as are individuals not covered by the mapping
+    fn species_of(&self, label: String) -> String {
+        match &self.species {
+            Some(mapping) if !self.to_id.contains_key(&label) => match mapping.species(&label) {
+                Some(species) => species.into_owned(),
+                None => {
+                    warn!(
+                        "no species found for `{}`, treating it as its own species",
+                        label
+                    );
+                    label
+                }
+            },
+            _ => label,
         }
     }
 
     pub fn len(&self) -> usize {
         self.last
     }
+
+    /// Forget all taxa requested after the first `len` ones
+    pub fn truncate(&mut self, len: usize) {
+        for name in self.names.drain(len..) {
+            self.to_id.remove(&name);
+        }
+        self.last = self.last.min(len);
+    }
 }
 
 impl TreeCollection {
@@ -88,27 +293,271 @@ impl TreeCollection {
         TreeCollection {
             taxon_set: TaxonSet::new(),
             trees: Vec::new(),
+            weights: None,
+            nread: 0,
+            nfiltered: 0,
+            support_stats: SupportStats::default(),
+        }
+    }
+
+    /// A collection whose gene tree leaves are individuals of the species in `mapping`
+    pub fn with_species(mapping: SpeciesMapping) -> Self {
+        TreeCollection {
+            taxon_set: TaxonSet::with_species(mapping),
+            ..TreeCollection::new()
         }
     }
 
-    pub fn from_newick<P>(filename: P, config: &UstarConfig) -> Result<Self, &'static str>
+    /// Reads gene trees from a file, either newline delimited Newick or NEXUS
+    /// (recognized by its `#NEXUS` header)
+    pub fn from_file<P>(filename: P, config: &UstarConfig) -> Result<Self, InputError>
     where
         P: AsRef<Path>,
     {
-        let mut trees: Vec<Tree> = vec![];
-        let mut taxon_set = TaxonSet::new();
-        if let Ok(lines) = read_lines(filename) {
-            for line in lines {
-                if let Ok(newick) = line {
-                    let parsed = parse_newick(&mut taxon_set, newick.as_str(), config);
-                    trees.push(parsed);
-                } else {
-                    return Err("Error reading file");
+        let mut collection = TreeCollection::new();
+        collection.add_file(filename, config)?;
+        Ok(collection)
+    }
+
+    /// Appends the gene trees of another file, sharing the same taxon set
+    pub fn add_file<P>(&mut self, filename: P, config: &UstarConfig) -> Result<(), InputError>
+    where
+        P: AsRef<Path>,
+    {
+        self.read(filename.as_ref(), config, usize::MAX, &mut |_, _| {})
+    }
+
+    /// Reads the gene trees of a file in batches of at most `batch_size`, handing
+    /// each batch to `f` and dropping it right after, so that only one batch is
+    /// held in memory at a time. The taxon set keeps growing across batches.
+    pub fn stream_file<P, F>(
+        &mut self,
+        filename: P,
+        config: &UstarConfig,
+        batch_size: usize,
+        mut f: F,
+    ) -> Result<(), InputError>
+    where
+        P: AsRef<Path>,
+        F: FnMut(&TaxonSet, &[Tree]),
+    {
+        self.read(filename.as_ref(), config, batch_size.max(1), &mut f)?;
+        if !self.trees.is_empty() {
+            f(&self.taxon_set, &self.trees);
+            self.trees.clear();
+        }
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        path: &Path,
+        config: &UstarConfig,
+        batch_size: usize,
+        on_batch: BatchFn,
+    ) -> Result<(), InputError> {
+        let mut lines = read_lines(path)
+            .map_err(|source| InputError::io(path, source))?
+            .enumerate()
+            .peekable();
+        let mut nexus = false;
+        while let Some((_, line)) = lines.peek() {
+            match line {
+                Ok(line) if line.trim().is_empty() => {
+                    lines.next();
+                }
+                Ok(line) => {
+                    nexus = is_nexus_header(line);
+                    break;
                 }
+                Err(_) => break,
             }
-            Ok(TreeCollection { taxon_set, trees })
+        }
+        if nexus {
+            lines.next();
+            self.read_nexus(path, lines, config, batch_size, on_batch)
         } else {
-            Err("Could not read file")
+            self.read_newick(path, lines, config, batch_size, on_batch)
+        }
+    }
+
+    pub fn from_newick<P>(filename: P, config: &UstarConfig) -> Result<Self, InputError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = filename.as_ref();
+        let lines = read_lines(path).map_err(|source| InputError::io(path, source))?;
+        let mut collection = TreeCollection::new();
+        collection.read_newick(path, lines.enumerate(), config, usize::MAX, &mut |_, _| {})?;
+        Ok(collection)
+    }
+
+    /// One gene tree per line
+    fn read_newick<I>(
+        &mut self,
+        path: &Path,
+        lines: I,
+        config: &UstarConfig,
+        batch_size: usize,
+        on_batch: BatchFn,
+    ) -> Result<(), InputError>
+    where
+        I: Iterator<Item = (usize, io::Result<String>)>,
+    {
+        let mut skipped = 0usize;
+        for (i, line) in lines {
+            let newick = line.map_err(|source| InputError::io(path, source))?;
+            if newick.trim().is_empty() {
+                continue;
+            }
+            let added =
+                self.add_newick(&newick, None, None, config, |source| InputError::Newick {
+                    path: path.to_path_buf(),
+                    line: i + 1,
+                    source,
+                })?;
+            if !added {
+                skipped += 1;
+            } else if self.trees.len() >= batch_size {
+                on_batch(&self.taxon_set, &self.trees);
+                self.trees.clear();
+            }
+        }
+        if skipped > 0 {
+            warn!("skipped {} invalid gene tree(s)", skipped);
+        }
+        Ok(())
+    }
+
+    /// `TREE` commands of all `TREES` blocks, resolving `TRANSLATE` aliases
+    fn read_nexus<I>(
+        &mut self,
+        path: &Path,
+        lines: I,
+        config: &UstarConfig,
+        batch_size: usize,
+        on_batch: BatchFn,
+    ) -> Result<(), InputError>
+    where
+        I: Iterator<Item = (usize, io::Result<String>)>,
+    {
+        let mut skipped = 0usize;
+        let mut in_trees = false;
+        let mut translate = HashMap::new();
+        for command in Commands::new(lines) {
+            let command = command.map_err(|source| InputError::io(path, source))?;
+            let (keyword, rest) = command.keyword();
+            match keyword.as_str() {
+                "begin" => {
+                    in_trees = command.text[rest..].trim().eq_ignore_ascii_case("trees");
+                    translate.clear();
+                }
+                "end" | "endblock" => in_trees = false,
+                "translate" if in_trees => translate = parse_translate(&command.text[rest..]),
+                "tree" | "utree" if in_trees => {
+                    let start = tree_body(&command.text, rest).ok_or_else(|| {
+                        let (line, column) = command.locate(rest, 1);
+                        InputError::Newick {
+                            path: path.to_path_buf(),
+                            line,
+                            source: NewickError {
+                                column,
+                                expected: "'=' after the tree name",
+                                found: None,
+                            },
+                        }
+                    })?;
+                    let table = if translate.is_empty() {
+                        None
+                    } else {
+                        Some(&translate)
+                    };
+                    let name = tree_name(&command.text[rest..start - 1]);
+                    let added = self.add_newick(
+                        &command.text[start..],
+                        Some(&name),
+                        table,
+                        config,
+                        |source| {
+                            let (line, column) = command.locate(start, source.column);
+                            InputError::Newick {
+                                path: path.to_path_buf(),
+                                line,
+                                source: NewickError { column, ..source },
+                            }
+                        },
+                    )?;
+                    if !added {
+                        skipped += 1;
+                    } else if self.trees.len() >= batch_size {
+                        on_batch(&self.taxon_set, &self.trees);
+                        self.trees.clear();
+                    }
+                }
+                _ => {}
+            }
+        }
+        if skipped > 0 {
+            warn!("skipped {} invalid gene tree(s)", skipped);
+        }
+        Ok(())
+    }
+
+    /// Parses and appends a single gene tree, or the single-copy trees it is
+    /// decomposed into. Returns `false` if the tree was malformed and dropped
+    /// because of `UstarConfig::skip_invalid`
+    fn add_newick<F>(
+        &mut self,
+        newick: &str,
+        name: Option<&str>,
+        translate: Option<&HashMap<String, String>>,
+        config: &UstarConfig,
+        locate: F,
+    ) -> Result<bool, InputError>
+    where
+        F: FnOnce(NewickError) -> InputError,
+    {
+        let ntaxa = self.taxon_set.len();
+        self.nread += 1;
+        match parse_newick_translated(
+            &mut self.taxon_set,
+            newick,
+            config,
+            translate,
+            &mut self.support_stats,
+        ) {
+            Ok(mut parsed) => {
+                if let Some(weights) = &self.weights {
+                    parsed.weight = weights.weight(self.nread, name)?;
+                }
+                if config.contract_support.is_some() || config.contract_length.is_some() {
+                    contract(&mut parsed, config);
+                }
+                let covered = parsed.ntaxa();
+                if covered == 0 || covered < config.min_taxa {
+                    self.nfiltered += 1;
+                    self.taxon_set.truncate(ntaxa);
+                    return Ok(true);
+                }
+                match config.disco {
+                    Some(min_leaves) => {
+                        self.trees
+                            .extend(decompose(parsed, self.taxon_set.len(), min_leaves))
+                    }
+                    None => self.trees.push(parsed),
+                }
+                Ok(true)
+            }
+            Err(source) => {
+                let err = locate(source);
+                if !config.skip_invalid {
+                    return Err(err);
+                }
+                warn!("skipping invalid gene tree: {}", err);
+                // forget the taxa only seen in the partially parsed tree
+                self.taxon_set.truncate(ntaxa);
+                Ok(false)
+            }
         }
     }
 
@@ -116,6 +565,16 @@ impl TreeCollection {
         self.trees.len()
     }
 
+    /// The support values seen since the last call
+    pub fn take_support_stats(&mut self) -> SupportStats {
+        std::mem::take(&mut self.support_stats)
+    }
+
+    /// The number of gene trees dropped by `UstarConfig::min_taxa` so far
+    pub fn nfiltered(&self) -> usize {
+        self.nfiltered
+    }
+
     pub fn ntaxa(&self) -> usize {
         self.taxon_set.len()
     }
@@ -132,6 +591,16 @@ pub struct Tree {
     pub childcount: Vec<u32>,
     pub fake_root: bool,
     pub root: usize,
+    pub annotations: HashMap<usize, Annotations>, // sparse, only nodes with comments
+    pub weight: f64, // weight of the whole tree in the averaged distances
+}
+
+/// Comments attached to a node, kept only when `UstarConfig::keep_comments` is set
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Annotations {
+    pub comments: Vec<String>,
+    /// key/value pairs found in NHX or `[&key=value]` comments
+    pub attributes: Vec<(String, String)>,
 }
 
 impl Tree {
@@ -157,6 +626,8 @@ impl Tree {
             childcount,
             fake_root,
             root: 0,
+            annotations: HashMap::new(),
+            weight: 1.0,
         }
     }
 
@@ -179,11 +650,230 @@ impl Tree {
         node == self.root
     }
 
+    /// Contracts the internal branches for which `collapse` holds, attaching the
+    /// children of their lower node to the upper one, and returns their count
+    pub fn contract<F>(&mut self, collapse: F) -> usize
+    where
+        F: Fn(&Tree, usize) -> bool,
+    {
+        let n = self.taxa.len();
+        let mut tree = Tree {
+            taxa: Vec::with_capacity(n),
+            parents: Vec::with_capacity(n),
+            support: Vec::with_capacity(n),
+            lengths: Vec::with_capacity(n),
+            firstchild: Vec::with_capacity(n),
+            nextsib: Vec::with_capacity(n),
+            childcount: Vec::with_capacity(n),
+            fake_root: false,
+            root: 0,
+            annotations: HashMap::new(),
+            weight: self.weight,
+        };
+        let mut contracted = 0usize;
+        // the last child of each new node so far, to chain the next one
+        let mut lastchild: Vec<i32> = Vec::with_capacity(n);
+        // (old node, new parent), children pushed last to first
+        let mut stack = vec![(self.root, -1i32)];
+        while let Some((v, parent)) = stack.pop() {
+            if parent >= 0 && !self.is_leaf(v) && collapse(self, v) {
+                contracted += 1;
+                let children: Vec<usize> = self.children(v).collect();
+                stack.extend(children.into_iter().rev().map(|c| (c, parent)));
+                continue;
+            }
+            let i = tree.taxa.len();
+            tree.taxa.push(self.taxa[v]);
+            tree.parents.push(parent.max(0));
+            tree.support.push(self.support[v]);
+            tree.lengths.push(self.lengths[v]);
+            tree.firstchild.push(-1);
+            tree.nextsib.push(-1);
+            tree.childcount.push(0);
+            lastchild.push(-1);
+            if let Some(it) = self.annotations.remove(&v) {
+                tree.annotations.insert(i, it);
+            }
+            if parent >= 0 {
+                let p = parent as usize;
+                tree.childcount[p] += 1;
+                // chain the children in the order they are popped
+                match lastchild[p] {
+                    -1 => tree.firstchild[p] = i as i32,
+                    last => tree.nextsib[last as usize] = i as i32,
+                }
+                lastchild[p] = i as i32;
+            }
+            let children: Vec<usize> = self.children(v).collect();
+            stack.extend(children.into_iter().rev().map(|c| (c, i as i32)));
+        }
+        tree.fake_root = self.fake_root && tree.childcount[0] == 2;
+        *self = tree;
+        contracted
+    }
+
+    /// Removes the leaves for which `keep` does not hold and the internal nodes
+    /// left without leaves, suppressing the resulting degree-2 nodes. The two
+    /// edges around a suppressed node become one, adding up their lengths and
+    /// keeping the higher support. Returns the number of leaves left.
+    pub fn prune<F>(&mut self, keep: F) -> usize
+    where
+        F: Fn(&Tree, usize) -> bool,
+    {
+        let n = self.taxa.len();
+        let mut alive = vec![0usize; n];
+        for v in self.postorder() {
+            alive[v] = if self.is_leaf(v) {
+                keep(self, v) as usize
+            } else {
+                self.children(v).map(|c| alive[c]).sum()
+            };
+        }
+        let mut lengths = self.lengths.clone();
+        if self.fake_root {
+            // split the root edge again, its halves are merged back below
+            let c = self.firstchild[self.root] as usize;
+            lengths[self.nextsib[c] as usize] = 0.0;
+        }
+        let mut annotations = std::mem::take(&mut self.annotations);
+        let alive_children =
+            |v: usize| -> Vec<usize> { self.children(v).filter(|&c| alive[c] > 0).collect() };
+        let mut root = self.root;
+        while let [c] = alive_children(root)[..] {
+            root = c;
+        }
+        let mut tree = Tree {
+            taxa: Vec::with_capacity(n),
+            parents: Vec::with_capacity(n),
+            support: Vec::with_capacity(n),
+            lengths: Vec::with_capacity(n),
+            firstchild: Vec::with_capacity(n),
+            nextsib: Vec::with_capacity(n),
+            childcount: Vec::with_capacity(n),
+            fake_root: false,
+            root: 0,
+            annotations: HashMap::new(),
+            weight: self.weight,
+        };
+        if let Some(it) = annotations.remove(&self.root) {
+            annotations.insert(root, it);
+        }
+        let mut lastchild: Vec<i32> = Vec::with_capacity(n);
+        let mut stack = vec![(root, -1i32, self.support[root], lengths[root])];
+        while let Some((mut v, parent, mut support, mut length)) = stack.pop() {
+            if parent >= 0 {
+                while let [c] = alive_children(v)[..] {
+                    v = c;
+                    support = support.max(self.support[c]);
+                    length += lengths[c];
+                }
+            }
+            let i = tree.taxa.len();
+            tree.taxa
+                .push(if self.is_leaf(v) { self.taxa[v] } else { -1 });
+            tree.parents.push(parent.max(0));
+            tree.support.push(support);
+            tree.lengths.push(length);
+            tree.firstchild.push(-1);
+            tree.nextsib.push(-1);
+            tree.childcount.push(0);
+            lastchild.push(-1);
+            if let Some(it) = annotations.remove(&v) {
+                tree.annotations.insert(i, it);
+            }
+            if parent >= 0 {
+                let p = parent as usize;
+                tree.childcount[p] += 1;
+                match lastchild[p] {
+                    -1 => tree.firstchild[p] = i as i32,
+                    last => tree.nextsib[last as usize] = i as i32,
+                }
+                lastchild[p] = i as i32;
+            }
+            let children = alive_children(v);
+            stack.extend(
+                children
+                    .into_iter()
+                    .rev()
+                    .map(|c| (c, i as i32, self.support[c], lengths[c])),
+            );
+        }
+        if tree.childcount[0] == 2 {
+            let c = tree.firstchild[0] as usize;
+            let c2 = tree.nextsib[c] as usize;
+            tree.fake_root = true;
+            let support = tree.support[c].max(tree.support[c2]);
+            let length = tree.lengths[c] + tree.lengths[c2];
+            for it in [c, c2] {
+                tree.support[it] = support;
+                tree.lengths[it] = length;
+            }
+        }
+        *self = tree;
+        alive[root]
+    }
+
+    /// The number of distinct taxa at the leaves
+    pub fn ntaxa(&self) -> usize {
+        let mut taxa: Vec<i32> = self
+            .postorder()
+            .filter(|&v| self.is_leaf(v) && self.taxa[v] >= 0)
+            .map(|v| self.taxa[v])
+            .collect();
+        taxa.sort_unstable();
+        taxa.dedup();
+        taxa.len()
+    }
+
+    /// The mean branch length, counting the two edges below a degree-2 root
+    /// as one, `None` if the tree has no positive branch length
+    pub fn mean_length(&self) -> Option<f64> {
+        let total = self.total_length();
+        if total > 0.0 {
+            Some(total / self.branches().count() as f64)
+        } else {
+            None
+        }
+    }
+
+    /// The sum of the branch lengths, counting the two edges below a degree-2
+    /// root as one
+    pub fn total_length(&self) -> f64 {
+        self.branches().map(|v| self.lengths[v]).sum()
+    }
+
+    /// The nodes below each branch, skipping one of the two nodes below a
+    /// degree-2 root as they share their branch
+    fn branches(&self) -> impl Iterator<Item = usize> + '_ {
+        let skipped = if self.fake_root {
+            self.lastchild(self.root)
+        } else {
+            None
+        };
+        self.postorder()
+            .filter(move |&v| !self.is_root(v) && Some(v) != skipped)
+    }
+
+    fn lastchild(&self, node: usize) -> Option<usize> {
+        self.children(node).last()
+    }
+
+    /// Looks up an annotated attribute of a node, e.g. `B` in `[&&NHX:B=90]`
+    pub fn attribute(&self, node: usize, key: &str) -> Option<&str> {
+        self.annotations
+            .get(&node)?
+            .attributes
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
     pub fn topology_newick(&self, taxon_set: &TaxonSet) -> String {
         let mut string_rep: Vec<String> = vec![String::new(); self.taxa.len()];
         for node in self.postorder() {
             if self.is_leaf(node) {
-                string_rep[node] = taxon_set.names[self.taxa[node] as usize].clone();
+                string_rep[node] =
+                    quote_label(&taxon_set.names[self.taxa[node] as usize]).into_owned();
             } else {
                 let mut out = String::new();
                 out.push_str("(");
@@ -199,9 +889,113 @@ impl Tree {
         string_rep[self.root].push_str(";");
         string_rep.swap_remove(self.root)
     }
+
+    /// Like `topology_newick`, with the branch lengths
+    pub fn newick(&self, taxon_set: &TaxonSet) -> String {
+        let mut string_rep: Vec<String> = vec![String::new(); self.taxa.len()];
+        for node in self.postorder() {
+            let mut out = String::new();
+            if self.is_leaf(node) {
+                out.push_str(&quote_label(&taxon_set.names[self.taxa[node] as usize]));
+            } else {
+                out.push('(');
+                for c in self.children(node) {
+                    out.push_str(&string_rep[c]);
+                    out.push(',');
+                }
+                out.pop();
+                out.push(')');
+            }
+            if !self.is_root(node) {
+                out.push_str(&format!(":{}", self.lengths[node]));
+            }
+            string_rep[node] = out;
+        }
+        string_rep[self.root].push(';');
+        string_rep.swap_remove(self.root)
+    }
+}
+
+/// Builds a tree bottom up, the leaves being the first `n` nodes
+pub struct TreeBuilder {
+    tree: Tree,
 }
 
-pub fn parse_newick(taxon_set: &mut TaxonSet, newick: &str, config: &UstarConfig) -> Tree {
+impl TreeBuilder {
+    pub fn new(n: usize) -> Self {
+        let tree = Tree {
+            taxa: (0..n as i32).collect(),
+            parents: vec![-1; n],
+            support: vec![0.0; n],
+            lengths: vec![0.0; n],
+            firstchild: vec![-1; n],
+            nextsib: vec![-1; n],
+            childcount: vec![0; n],
+            fake_root: false,
+            root: 0,
+            annotations: HashMap::new(),
+            weight: 1.0,
+        };
+        TreeBuilder { tree }
+    }
+
+    /// Adds a parent to the `(node, branch length)` children and returns it
+    pub fn join(&mut self, children: &[(usize, f64)]) -> usize {
+        let tree = &mut self.tree;
+        let parent = tree.taxa.len();
+        tree.taxa.push(-1);
+        tree.parents.push(-1);
+        tree.support.push(0.0);
+        tree.lengths.push(0.0);
+        tree.firstchild.push(children[0].0 as i32);
+        tree.nextsib.push(-1);
+        tree.childcount.push(children.len() as u32);
+        for (x, &(c, length)) in children.iter().enumerate() {
+            tree.parents[c] = parent as i32;
+            tree.lengths[c] = length;
+            if let Some(&(next, _)) = children.get(x + 1) {
+                tree.nextsib[c] = next as i32;
+            }
+        }
+        parent
+    }
+
+    /// Joins the subtrees left at the root, unless there is a single one
+    pub fn finish(mut self, children: &[(usize, f64)]) -> Tree {
+        if children.len() > 1 {
+            self.tree.root = self.join(children);
+            self.tree.fake_root = children.len() == 2;
+        } else if let Some(&(root, _)) = children.first() {
+            self.tree.root = root;
+        }
+        self.tree
+    }
+}
+
+pub fn parse_newick(
+    taxon_set: &mut TaxonSet,
+    newick: &str,
+    config: &UstarConfig,
+) -> Result<Tree, NewickError> {
+    parse_newick_translated(
+        taxon_set,
+        newick,
+        config,
+        None,
+        &mut SupportStats::default(),
+    )
+}
+
+/// Like `parse_newick`, but leaf labels found in `translate` (e.g., a NEXUS
+/// `TRANSLATE` table) are replaced by the taxon names they stand for, and the
+/// support values are recorded into `stats`
+pub fn parse_newick_translated(
+    taxon_set: &mut TaxonSet,
+    newick: &str,
+    config: &UstarConfig,
+    translate: Option<&HashMap<String, String>>,
+    stats: &mut SupportStats,
+) -> Result<Tree, NewickError> {
     let mut taxa: Vec<i32> = vec![-42];
     let mut parents: Vec<i32> = vec![0];
     let mut support: Vec<f64> = vec![-1.0];
@@ -209,74 +1003,153 @@ pub fn parse_newick(taxon_set: &mut TaxonSet, newick: &str, config: &UstarConfig
     let mut childcount: Vec<u32> = vec![0];
     let mut firstchild: Vec<i32> = vec![-1];
     let mut nextsib: Vec<i32> = vec![-1];
+    let mut annotations: HashMap<usize, Annotations> = HashMap::new();
+    let mut weight = 1.0;
+    // if some leaves are removed by the taxon filter
+    let mut pruned = false;
     // we just reuse TreeSwift's logic
     let mut n: usize = 0; // the current node
-    let mut chars = newick.chars().fuse().peekable();
-    while let Some(c) = chars.next() {
-        if c == ';' {
-            break;
-        } else if c == '(' {
-            taxa.push(-1);
-            childcount[n as usize] += 1;
-            parents.push(n as i32);
-            support.push(0.0);
-            lengths.push(0.0);
-            childcount.push(0);
-            firstchild.push(-1);
-            nextsib.push(-1);
-            firstchild[n] = (taxa.len() - 1) as i32;
-            n = taxa.len() - 1;
-        } else if c == ')' {
-            n = parents[n] as usize;
-        } else if c == ',' {
-            nextsib[n] = (taxa.len()) as i32;
-            n = parents[n] as usize;
-            taxa.push(-1);
-            childcount[n as usize] += 1;
-            parents.push(n as i32);
-            support.push(0.0);
-            lengths.push(0.0);
-            childcount.push(0);
-            firstchild.push(-1);
-            nextsib.push(-1);
-            n = taxa.len() - 1;
-        } else if c == ':' {
-            let mut ls = "".to_string();
-            loop {
-                match chars.peek() {
-                    Some(',') | Some(')') | Some(';') | Some('[') | None => {
-                        break;
-                    }
-                    Some(_) => {
-                        ls.push(chars.next().unwrap());
-                    }
+    let mut labeled = false; // if the current node already has a label
+    let mut after_colon = false;
+    for token in Lexer::new(newick) {
+        let (pos, token) = token?;
+        let expecting_length = std::mem::replace(&mut after_colon, false);
+        match token {
+            Token::Semicolon => {
+                if childcount[n] == 0 && taxa[n] < 0 {
+                    return Err(NewickError::new(pos, "a taxon label", Some(token)));
+                }
+                if n != 0 {
+                    return Err(NewickError::new(pos, "')'", Some(token)));
                 }
+                break;
             }
-            if !ls.is_empty() {
-                lengths[n as usize] = ls.parse::<f64>().unwrap();
+            Token::Open => {
+                if labeled {
+                    return Err(NewickError::new(pos, "',' or ')'", Some(token)));
+                }
+                // a `(` right after a `)` would replace the children of `n`
+                if childcount[n] > 0 {
+                    let expected = if n == 0 { "',' or ';'" } else { "',' or ')'" };
+                    return Err(NewickError::new(pos, expected, Some(token)));
+                }
+                taxa.push(-1);
+                childcount[n] += 1;
+                parents.push(n as i32);
+                support.push(0.0);
+                lengths.push(0.0);
+                childcount.push(0);
+                firstchild.push(-1);
+                nextsib.push(-1);
+                firstchild[n] = (taxa.len() - 1) as i32;
+                n = taxa.len() - 1;
             }
-        } else {
-            let mut ts = c.to_string();
-            loop {
-                match chars.peek() {
-                    Some(':') | Some(',') | Some(')') | Some(';') | Some('[') | None => {
-                        break;
+            Token::Close => {
+                if childcount[n] == 0 && taxa[n] < 0 {
+                    return Err(NewickError::new(pos, "a taxon label", Some(token)));
+                }
+                if n == 0 {
+                    return Err(NewickError::new(pos, "';'", Some(token)));
+                }
+                n = parents[n] as usize;
+                labeled = false;
+            }
+            Token::Comma => {
+                if childcount[n] == 0 && taxa[n] < 0 {
+                    return Err(NewickError::new(pos, "a taxon label", Some(token)));
+                }
+                if n == 0 {
+                    return Err(NewickError::new(pos, "';'", Some(token)));
+                }
+                nextsib[n] = (taxa.len()) as i32;
+                n = parents[n] as usize;
+                taxa.push(-1);
+                childcount[n] += 1;
+                parents.push(n as i32);
+                support.push(0.0);
+                lengths.push(0.0);
+                childcount.push(0);
+                firstchild.push(-1);
+                nextsib.push(-1);
+                n = taxa.len() - 1;
+                labeled = false;
+            }
+            Token::Colon => {
+                after_colon = true;
+            }
+            Token::Comment(comment) => {
+                // comments may sit between the colon and the branch length
+                after_colon = expecting_length;
+                if config.weight_annotations && n == 0 {
+                    // e.g. `[&W 1/3]` before the tree, as written by PAUP*
+                    let attributes = parse_attributes(&comment);
+                    if let Some((_, w)) = attributes.iter().find(|(k, _)| k == "W") {
+                        weight = parse_weight(w)
+                            .ok_or_else(|| NewickError::new(pos, "a tree weight", Some(w)))?;
                     }
-                    Some(_) => {
-                        ts.push(chars.next().unwrap());
+                }
+                if config.keep_comments {
+                    let attributes = parse_attributes(&comment);
+                    // e.g. `[&support=0.95]` or `[&&NHX:B=95]` after an internal node
+                    let annotated = attributes
+                        .iter()
+                        .find(|(k, _)| k == "support" || k == "B")
+                        .filter(|_| childcount[n] > 0);
+                    if let Some((_, s)) = annotated {
+                        let s = s.trim().parse::<f64>().map_err(|_| {
+                            NewickError::new(pos, "a numeric support value", Some(s))
+                        })?;
+                        support[n] = scaled_support(s, 1, config, stats);
                     }
+                    let entry = annotations.entry(n).or_default();
+                    entry.attributes.extend(attributes);
+                    entry.comments.push(comment);
                 }
             }
-            if childcount[n] == 0 {
-                taxa[n] = taxon_set.request(ts) as i32;
-                support[n] = 1.0;
-            } else {
-                let s = ts.parse::<f64>().unwrap();
-                let rg = config.upper_bound - config.lower_bound;
-                support[n] = ((s - config.lower_bound) / rg).max(0.0);
+            Token::Label(ls) if expecting_length => {
+                lengths[n] = ls
+                    .parse::<f64>()
+                    .map_err(|_| NewickError::new(pos, "a branch length", Some(ls)))?;
+            }
+            Token::Label(ts) => {
+                if labeled {
+                    return Err(NewickError::new(pos, "':', ',' or ')'", Some(ts)));
+                }
+                labeled = true;
+                if childcount[n] == 0 {
+                    let ts = match translate.and_then(|it| it.get(&ts)) {
+                        Some(name) => name.clone(),
+                        None => ts,
+                    };
+                    taxa[n] = if taxon_set.is_filtered(&ts) {
+                        pruned = true;
+                        PRUNED
+                    } else {
+                        taxon_set.request(ts) as i32
+                    };
+                    support[n] = 1.0;
+                } else {
+                    // e.g. `95/100` for IQ-TREE's SH-aLRT/UFBoot
+                    let fields: Vec<&str> = ts.split('/').collect();
+                    let s = fields
+                        .get(config.support_field)
+                        .or_else(|| fields.last())
+                        .and_then(|it| it.trim().parse::<f64>().ok())
+                        .ok_or_else(|| {
+                            NewickError::new(pos, "a numeric support value", Some(&ts))
+                        })?;
+                    support[n] = scaled_support(s, fields.len(), config, stats);
+                }
             }
         }
     }
+    if n != 0 {
+        return Err(NewickError::new(
+            newick.chars().count(),
+            "')'",
+            None::<char>,
+        ));
+    }
 
     let mut fake_root = false;
     if childcount[0] == 2 {
@@ -294,7 +1167,7 @@ pub fn parse_newick(taxon_set: &mut TaxonSet, newick: &str, config: &UstarConfig
         lengths[c2] = length;
     }
 
-    Tree {
+    let mut tree = Tree {
         taxa,
         parents,
         support,
@@ -304,9 +1177,88 @@ pub fn parse_newick(taxon_set: &mut TaxonSet, newick: &str, config: &UstarConfig
         childcount,
         fake_root,
         root: 0,
+        annotations,
+        weight,
+    };
+    if pruned {
+        tree.prune(|t, v| t.taxa[v] != PRUNED);
+    }
+    Ok(tree)
+}
+
+/// Records the support value `s` read from a node and rescales it from the
+/// bounds of `config` to [0, 1]
+fn scaled_support(s: f64, fields: usize, config: &UstarConfig, stats: &mut SupportStats) -> f64 {
+    stats.record(s, fields, config.lower_bound, config.upper_bound);
+    let rg = config.upper_bound - config.lower_bound;
+    ((s - config.lower_bound) / rg).max(0.0)
+}
+
+/// Placeholder taxon of leaves removed by the taxon filter while parsing
+const PRUNED: i32 = i32::MAX;
+
+/// Contracts the branches below the thresholds of `config`. Support is compared
+/// after the same rescaling as in `parse_newick`, branches without support
+/// counting as having the lowest support
+fn contract(tree: &mut Tree, config: &UstarConfig) {
+    let min_support = config
+        .contract_support
+        .map(|s| (s - config.lower_bound) / (config.upper_bound - config.lower_bound));
+    tree.contract(|t, v| {
+        matches!(min_support, Some(s) if t.support[v] < s)
+            || matches!(config.contract_length, Some(l) if t.lengths[v] < l)
+    });
+}
+
+/// Failure to load a gene tree file, pointing back to the offending file and line
+#[derive(Debug)]
+pub enum InputError {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Newick {
+        path: PathBuf,
+        /// 1-based line number, i.e., the gene index for Newick input without blank lines
+        line: usize,
+        source: NewickError,
+    },
+}
+
+impl InputError {
+    pub(crate) fn io(path: &Path, source: io::Error) -> Self {
+        InputError::Io {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputError::Io { path, source } => {
+                write!(f, "could not read {}: {}", path.display(), source)
+            }
+            InputError::Newick { path, line, source } => write!(
+                f,
+                "malformed gene tree at {}:{}:{}: expected {}, found {}",
+                path.display(),
+                line,
+                source.column,
+                source.expected,
+                source
+                    .found
+                    .as_ref()
+                    .map(|it| format!("`{}`", it))
+                    .unwrap_or_else(|| "end of input".to_string())
+            ),
+        }
     }
 }
 
+impl Error for InputError {}
+
 pub struct PostorderIterator {
     // s1 : Vec<usize>,
     s2: Vec<usize>,
@@ -363,3 +1315,127 @@ impl Iterator for PostorderIterator {
         self.s2.pop()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> HashSet<String> {
+        names.iter().map(|it| it.to_string()).collect()
+    }
+
+    fn filtered_set(mapped: bool, filter: TaxonFilter) -> TaxonSet {
+        let mut taxon_set = if mapped {
+            TaxonSet::with_species(SpeciesMapping::Delimiter("_".to_string()))
+        } else {
+            TaxonSet::new()
+        };
+        taxon_set.set_filter(filter);
+        taxon_set
+    }
+
+    #[test]
+    fn filter_by_label_or_species() {
+        // (species mapped, listed names, label, whether the label is listed)
+        let cases: &[(bool, &[&str], &str, bool)] = &[
+            (false, &["A"], "A", true),
+            (false, &["A"], "B", false),
+            (true, &["Hs", "Pt_1"], "Hs_1", true),
+            (true, &["Hs", "Pt_1"], "Hs_2", true),
+            (true, &["Hs", "Pt_1"], "Pt_1", true),
+            (true, &["Hs", "Pt_1"], "Pt_2", false),
+            (true, &["Hs", "Pt_1"], "Gg_1", false),
+        ];
+        for &(mapped, listed, label, matched) in cases {
+            let excluded = filtered_set(mapped, TaxonFilter::Exclude(names(listed)));
+            assert_eq!(excluded.is_filtered(label), matched, "excluding {}", label);
+            let included = filtered_set(mapped, TaxonFilter::Include(names(listed)));
+            assert_eq!(included.is_filtered(label), !matched, "including {}", label);
+        }
+    }
+
+    #[test]
+    fn contract_and_prune_keep_child_order() {
+        let mut taxon_set = TaxonSet::new();
+        let newick = "((A:1,B:1)0.1:1,(C:1,D:1)0.9:1,(E:1,F:1,G:1)0.2:1,H:1);";
+        let mut tree = parse_newick(&mut taxon_set, newick, &UstarConfig::default()).unwrap();
+        assert_eq!(tree.contract(|t, v| t.support[v] < 0.5), 2);
+        assert_eq!(
+            tree.newick(&taxon_set),
+            "(A:1,B:1,(C:1,D:1):1,E:1,F:1,G:1,H:1);"
+        );
+        let d = taxon_set.retrieve("D");
+        assert_eq!(tree.prune(|t, v| t.taxa[v] as usize != d), 7);
+        assert_eq!(tree.newick(&taxon_set), "(A:1,B:1,C:2,E:1,F:1,G:1,H:1);");
+    }
+
+    #[test]
+    fn support_from_annotations() {
+        let mut taxon_set = TaxonSet::new();
+        let config = UstarConfig {
+            keep_comments: true,
+            ..UstarConfig::default()
+        };
+        let newick = "((A,B)[&support=0.9],(C,D)0.2[&&NHX:S=x:B=0.5],E);";
+        let tree = parse_newick(&mut taxon_set, newick, &config).unwrap();
+        let (ab, cd) = (1, 4);
+        assert_eq!(tree.support[ab], 0.9);
+        assert_eq!(tree.support[cd], 0.5);
+        assert_eq!(tree.attribute(cd, "S"), Some("x"));
+        let tree = parse_newick(&mut taxon_set, newick, &UstarConfig::default()).unwrap();
+        assert_eq!(tree.support[ab], 0.0);
+        assert_eq!(tree.support[cd], 0.2);
+        assert!(tree.annotations.is_empty());
+    }
+
+    #[test]
+    fn open_after_close_is_rejected() {
+        let mut taxon_set = TaxonSet::new();
+        for (newick, column, expected) in [
+            ("(A)(B);", 4, "',' or ';'"),
+            ("(A,B)(C,D);", 6, "',' or ';'"),
+            ("((A)(B));", 5, "',' or ')'"),
+        ] {
+            let error = parse_newick(&mut taxon_set, newick, &UstarConfig::default()).unwrap_err();
+            assert_eq!((error.column, error.expected), (column, expected));
+            assert_eq!(error.found.as_deref(), Some("("));
+        }
+    }
+
+    #[test]
+    fn excluded_species_are_pruned() {
+        let mut taxon_set = filtered_set(true, TaxonFilter::Exclude(names(&["Hs", "Pt_1"])));
+        let tree = parse_newick(
+            &mut taxon_set,
+            "((Hs_1,Pt_1),(Pt_2,Gg_1));",
+            &UstarConfig::default(),
+        )
+        .unwrap();
+        let mut leaves: Vec<&str> = tree
+            .postorder()
+            .filter(|&v| tree.is_leaf(v))
+            .map(|v| taxon_set.names[tree.taxa[v] as usize].as_str())
+            .collect();
+        leaves.sort_unstable();
+        assert_eq!(leaves, ["Gg", "Pt"]);
+    }
+
+    #[test]
+    fn nexus_trees_with_translate() {
+        let nexus = "begin trees;\n  translate 1 'Homo sapiens', 2 Pan;\n  \
+                     tree 'gene 1' = (1,2,C);\n  tree * g2 = [&U] (2,(1,C));\nend;";
+        let lines = nexus.lines().map(|it| Ok(it.to_string())).enumerate();
+        let mut collection = TreeCollection::new();
+        collection
+            .read_nexus(
+                Path::new("genes.nex"),
+                lines,
+                &UstarConfig::default(),
+                usize::MAX,
+                &mut |_, _| {},
+            )
+            .unwrap();
+        assert_eq!(collection.trees.len(), 2);
+        assert_eq!(collection.taxon_set.names, ["Homo sapiens", "Pan", "C"]);
+    }
+}
@@ -7,7 +7,7 @@ use crate::tree::Tree;
 
 type MinNotNan = Reverse<NotNan<f64>>;
 /// UPGMA*, see Pranjal's thesis section 5.2.1
-pub fn upgma_star(distance: &Array<f64, Ix2>, mask: &Array<u32, Ix2>) -> anyhow::Result<Tree> {
+pub fn upgma_star(distance: &Array<f64, Ix2>, mask: &Array<f64, Ix2>) -> anyhow::Result<Tree> {
     let n = distance.shape()[0];
     let mut m = Array::<f64, _>::zeros((n * 2, n * 2).f());
     let mut known = Array::<u8, _>::zeros((n * 2, n * 2).f());
@@ -19,7 +19,7 @@ pub fn upgma_star(distance: &Array<f64, Ix2>, mask: &Array<u32, Ix2>) -> anyhow:
     tree.root = 2 * n - 2;
     for i in 0..n - 1 {
         for j in i + 1..n {
-            if mask[[i, j]] > 0 {
+            if mask[[i, j]] > 0.0 {
                 m[[i, j]] = distance[[i, j]];
                 known[[i, j]] = 1;
                 pq.push((Reverse(NotNan::new(m[[i, j]])?), i, j));
@@ -0,0 +1,101 @@
+use crate::tree::{open_input, InputError};
+use std::collections::HashMap;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+
+/// Per gene tree weights read from a side file
+#[derive(Debug, Clone)]
+pub struct GeneWeights {
+    path: PathBuf,
+    weights: Weights,
+}
+
+#[derive(Debug, Clone)]
+enum Weights {
+    /// one weight per line, in the order the gene trees are read
+    Positional(Vec<f64>),
+    /// `id weight` per line, the id being a NEXUS tree name or a 1-based gene tree index
+    Named(HashMap<String, f64>),
+}
+
+impl GeneWeights {
+    pub fn from_file(path: &Path) -> Result<Self, InputError> {
+        let weights = read_weights(path).map_err(|source| InputError::io(path, source))?;
+        Ok(GeneWeights {
+            path: path.to_path_buf(),
+            weights,
+        })
+    }
+
+    /// The weight of the `index`-th (1-based, over all input files) gene tree.
+    /// Gene trees missing from an `id weight` file have weight 1.
+    pub fn weight(&self, index: usize, name: Option<&str>) -> Result<f64, InputError> {
+        match &self.weights {
+            Weights::Positional(weights) => weights.get(index - 1).copied().ok_or_else(|| {
+                InputError::io(
+                    &self.path,
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("no weight for gene tree {}", index),
+                    ),
+                )
+            }),
+            Weights::Named(weights) => Ok(name
+                .and_then(|it| weights.get(it))
+                .or_else(|| weights.get(&index.to_string()))
+                .copied()
+                .unwrap_or(1.0)),
+        }
+    }
+}
+
+/// Parses a non-negative weight, either a number or a fraction such as `1/3`
+pub fn parse_weight(s: &str) -> Option<f64> {
+    let w = match s.trim().split_once('/') {
+        Some((a, b)) => a.trim().parse::<f64>().ok()? / b.trim().parse::<f64>().ok()?,
+        None => s.trim().parse::<f64>().ok()?,
+    };
+    if w.is_finite() && w >= 0.0 {
+        Some(w)
+    } else {
+        None
+    }
+}
+
+fn read_weights(path: &Path) -> io::Result<Weights> {
+    let mut positional = Vec::new();
+    let mut named = HashMap::new();
+    for (i, line) in open_input(path)?.lines().enumerate() {
+        let line = line?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (id, weight) = match fields[..] {
+            [] => continue,
+            [weight] => (None, weight),
+            [id, weight] => (Some(id), weight),
+            _ => return Err(invalid_line(i, "expected `weight` or `id weight`")),
+        };
+        let weight = parse_weight(weight)
+            .ok_or_else(|| invalid_line(i, "expected a non-negative weight"))?;
+        match id {
+            Some(id) => {
+                named.insert(id.to_string(), weight);
+            }
+            None => positional.push(weight),
+        }
+    }
+    match (positional.is_empty(), named.is_empty()) {
+        (_, true) => Ok(Weights::Positional(positional)),
+        (true, false) => Ok(Weights::Named(named)),
+        (false, false) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "mixed `weight` and `id weight` lines",
+        )),
+    }
+}
+
+fn invalid_line(i: usize, message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("line {}: {}", i + 1, message),
+    )
+}
@@ -98,7 +98,6 @@ tree *bionj (double **D, set *species, int n, boolean isNJ, const char *format)
 	a = (int*) mCalloc (1, sizeof (int));
 	b = (int*) mCalloc (1, sizeof (int));
 	chain1 = (char *) mCalloc (MAX_NAME_LENGTH, sizeof (char));
-	str = (char *) mCalloc (MAX_INPUT_SIZE, sizeof (char));
 
 	/* Create the delta matrix */
 	delta = (double **) mCalloc (n+1, sizeof (double*));
@@ -170,7 +169,7 @@ tree *bionj (double **D, set *species, int n, boolean isNJ, const char *format)
 		r = r-1;
 	}
 
-	FinishStr (delta, n, trees, str, format);	/* compute the branch-lengths */
+	str = FinishStr (delta, n, trees, format);	/* compute the branch-lengths */
 												/* of the last three subtrees */	
 
 	ret = readNewickString (str);
@@ -249,26 +248,94 @@ void Initialize (double **D, set *species, double **delta, POINTERS *trees, int
 ; input       :                                                             ;
 ;              int i           : indicate the subtree i to be printed.      ;
 ;              POINTERS *trees : pointer to the subtrees.                   ;
-:              char *output    : pointer to the output string.              ;
+:              char *output    : pointer to the end of the output string,   ;
+;                                large enough for the subtree.              ;
 ;                                                                           ;
-; return value: The phylogenetic tree in the output string.                 ;
+; return value: The new end of the output string.                           ;
 ;                                                                           ;
 \*;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;*/
 
 /*********************************************************/
 
-void Print_outputChar (int i, POINTERS *trees, char *output)
+char *Print_outputChar (int i, POINTERS *trees, char *output)
 {
 	WORD *parcour;
+	size_t length;
 
 	parcour = trees[i].head;
-	while (parcour != NULL && (strlen (output) + strlen (parcour->name) < MAX_INPUT_SIZE))
+	while (parcour != NULL)
 	{
-		output = strncat (output, parcour->name, strlen (parcour->name));
+		length = strlen (parcour->name);
+		memcpy (output, parcour->name, length);
+		output += length;
 		parcour = parcour->suiv;
 	}
+	*output = '\0';
 
-	return;
+	return (output);
+}
+
+/*;;;;;;;;;;;;;;;;;;;;;;;;;;;; Print_output ;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;*\
+;                                                                           ;
+;  Description : This function writes the last subtrees and their branch-   ;
+;                lengths in a string allocated to the size of the tree, and ;
+;                frees the subtrees.                                        ;
+;                                                                           ;
+;  input       :                                                            ;
+;                POINTERS *trees : list of subtrees                         ;
+;                int *last       : the last subtrees                        ;
+;                double *length  : the branch-lengths of the last subtrees  ;
+;                int k           : the number of last subtrees              ;
+;                                                                           ;
+;  return value:                                                            ;
+;                char *StrTree   : the output string                        ;
+;                                                                           ;
+\*;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;*/
+
+/*********************************************************/
+
+char *Print_output (POINTERS *trees, int *last, double *length, int k, const char *format)
+{
+	int i;
+	size_t size = 4;			/* "(" and ");\n"              */
+	char *StrTree;
+	char *end;
+	WORD *bidon;
+	WORD *ele;
+
+	for (i=0; i<k; i++)
+	{
+		for (bidon = trees[last[i]].head; NULL != bidon; bidon = bidon->suiv)
+			size += strlen (bidon->name);
+		size += (size_t) snprintf (NULL, 0, format, length[i]) + 2;
+	}
+
+	StrTree = (char *) mCalloc (size + 1, sizeof (char));
+	end = StrTree;
+	*end++ = '(';
+	for (i=0; i<k; i++)
+	{
+		end = Print_outputChar (last[i], trees, end);
+		*end++ = ':';
+		end += sprintf (end, format, length[i]);
+		if (i < k-1)
+			*end++ = ',';
+	}
+	strcpy (end, ");\n");
+
+	for (i=0; i<k; i++)
+	{
+		bidon = trees[last[i]].head;
+		ele = bidon;
+		while (NULL != bidon)
+		{
+			ele = ele->suiv;
+			free (bidon);
+			bidon = ele;
+		}
+	}
+
+	return (StrTree);
 }
 
 /*;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;*\
@@ -575,7 +642,6 @@ double Finish_branch_length (int i, int j, int k, double **delta)
 ;                double **delta  : the delta matrix                         ;
 ;                int n           : the number of taxa                       ;
 ;                POINTERS *trees : list of subtrees                         ;
-;                char *StrTree   : the output string                        ;
 ;                                                                           ;
 ;  return value:                                                            ;
 ;                char *StrTree   : the output string                        ;
@@ -584,14 +650,11 @@ double Finish_branch_length (int i, int j, int k, double **delta)
 
 /*********************************************************/
 
-void FinishStr (double **delta, int n, POINTERS *trees, char *StrTree, const char *format)
+char *FinishStr (double **delta, int n, POINTERS *trees, const char *format)
 {
 	int l = 1;
 	int i = 0;
-	double length;
-	char *tmp;
-	WORD *bidon;
-	WORD *ele;
+	double length[3];
 	int last[3];				/* the last three subtrees     */
 
 	while(l <= n)
@@ -604,66 +667,12 @@ void FinishStr (double **delta, int n, POINTERS *trees, char *StrTree, const cha
 		l++;
 	}
 
-	tmp = (char*) mCalloc (16, sizeof (char));
-	StrTree[0]='(';
-	length = Finish_branch_length (last[0], last[1], last[2], delta);
-	Print_outputChar (last[0], trees, StrTree);
-	//snprintf (tmp, 16, "%.6f,", length);
-	snprintf (tmp, INPUT_SIZE, format, length);
-	if (strlen (StrTree) + strlen (tmp) < MAX_INPUT_SIZE-2)
-	{
-		strncat (StrTree, ":", 1);
-		strncat (StrTree, tmp, strlen (tmp));
-		strncat (StrTree, ",", 1);
-	}
-
-	length = Finish_branch_length (last[1], last[0], last[2], delta);
-	Print_outputChar (last[1], trees, StrTree);
-	snprintf (tmp, INPUT_SIZE, format, length);
+	length[0] = Finish_branch_length (last[0], last[1], last[2], delta);
+	length[1] = Finish_branch_length (last[1], last[0], last[2], delta);
 	if (n>2)
-		strncat (tmp, ",", 1);
-/*		snprintf (tmp, 16, "%.6f,", length);
-	else
-		snprintf (tmp, 16, "%.6f", length);
-*/
-	if (strlen (StrTree) + strlen (tmp) < MAX_INPUT_SIZE-1)
-	{
-		strncat (StrTree, ":", 1);
-		strncat (StrTree, tmp, strlen (tmp));
-	}
-	
-	if (n>2) 
-	{
-		length = Finish_branch_length (last[2], last[1], last[0], delta);
-		Print_outputChar (last[2], trees, StrTree);
-		//snprintf (tmp, 16, "%.6f", length);
-		snprintf (tmp, INPUT_SIZE, format, length);
-		if (strlen (StrTree) + strlen (tmp) < MAX_INPUT_SIZE-2)
-		{
-			strncat (StrTree, ":", 1);
-			strncat (StrTree, tmp, strlen (tmp));
-		}
-	}
-
-	if (strlen (StrTree) < MAX_INPUT_SIZE - 3)
-		strncat (StrTree, ");\n", 3);
-
-	if (n>3)
-		n=3;
-	
-	for(i=0; i<n; i++)
-	{
-		bidon = trees[last[i]].head;
-		ele = bidon;
-		while (NULL != bidon)
-		{
-			ele = ele->suiv;
-			free (bidon);
-			bidon = ele;
-		}
-	}
+		length[2] = Finish_branch_length (last[2], last[1], last[0], delta);
 
-	return;
+	return (Print_output (trees, last, length, n>3 ? 3 : n, format));
 }
 
 /*;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;*\
@@ -57,7 +57,8 @@ typedef struct pointers
 
 tree *bionj (double **D, set *species, int n, boolean isNJ, const char *format);
 void Initialize (double **D, set *species, double **delta, POINTERS *trees, int n);
-void Print_outputChar (int i, POINTERS *trees, char *output);
+char *Print_outputChar (int i, POINTERS *trees, char *output);
+char *Print_output (POINTERS *trees, int *last, double *length, int k, const char *format);
 boolean Symmetrize (double **delta, int n);
 void Concatenate (char chain1[MAX_NAME_LENGTH], int ind, POINTERS *trees, int post);
 double Distance (int i, int j, double **delta);
@@ -67,7 +68,7 @@ double Sum_S (int i, double **delta);
 void Compute_sums_Sx (double **delta, int n);
 void Best_pair (double **delta, int r, int *a, int *b, int n);
 double Finish_branch_length (int i, int j, int k, double **delta);
-void FinishStr (double **delta, int n, POINTERS *trees, char *StrTree, const char *format);
+char *FinishStr (double **delta, int n, POINTERS *trees, const char *format);
 double Agglomerative_criterion (int i, int j, double **delta, int r);
 double Branch_length (int a, int b, double **delta, int r);
 double Reduction4 (int a, double la, int b, double lb, int i, double lamda, double **delta);
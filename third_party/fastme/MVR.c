@@ -73,7 +73,6 @@ tree *unj (double **D, set *species, int n, const char *format)
 	la = (double*) mCalloc (1, sizeof(double));
 	lb = (double*) mCalloc (1, sizeof(double));
 	chain1 = (char *) mCalloc (MAX_NAME_LENGTH, sizeof(char));
-	str = (char *) mCalloc (MAX_INPUT_SIZE, sizeof(char));
 
 	/*      Create the delta matrix     */
 	delta = (double **) mCalloc (n + 1, sizeof (double*));
@@ -145,7 +144,7 @@ tree *unj (double **D, set *species, int n, const char *format)
 		r = r-1;
 	}
 
-	FinishStrMVR (delta, n, trees, str, format);	/* compute the branch-lengths*/
+	str = FinishStrMVR (delta, n, trees, format);	/* compute the branch-lengths*/
 													/* of the last three subtrees*/
 	ret = readNewickString (str);
 	ret = detrifurcate (ret);
@@ -250,14 +249,11 @@ double Finish_branch_length_MVR (int i, int j, int k, double **delta, int n)
 
 /*********************************************************/
 
-void FinishStrMVR (double **delta, int n, POINTERS *trees, char *StrTree, const char *format)
+char *FinishStrMVR (double **delta, int n, POINTERS *trees, const char *format)
 {
 	int l = 1;
 	int i = 0;
-	double length;
-	char *tmp;
-	WORD *bidon;
-	WORD *ele;
+	double length[3];
 	int last[3];
 
 	while (l <= n)
@@ -270,70 +266,12 @@ void FinishStrMVR (double **delta, int n, POINTERS *trees, char *StrTree, const
 		l++;
 	}
 
-	tmp = (char*) mCalloc (MAX_NAME_LENGTH, sizeof(char));
-	StrTree[0] = '(';
-
-	length = Finish_branch_length_MVR (last[0], last[1], last[2], delta, n);
-	Print_outputChar (last[0], trees, StrTree);
-	//snprintf (tmp, 16, "%.6f,", length);
-	snprintf (tmp, DECIMAL_DIG+2, format, length);
-	if (strlen (StrTree) + strlen (tmp) < MAX_INPUT_SIZE-2)
-	{
-		strncat (StrTree, ":", 1);
-		strncat (StrTree, tmp, strlen (tmp));
-		strncat (StrTree, ",", 1);
-	}
-
-	length = Finish_branch_length_MVR (last[1], last[0], last[2], delta, n);
-	Print_outputChar (last[1], trees, StrTree);
-	snprintf (tmp, INPUT_SIZE, format, length);
+	length[0] = Finish_branch_length_MVR (last[0], last[1], last[2], delta, n);
+	length[1] = Finish_branch_length_MVR (last[1], last[0], last[2], delta, n);
 	if (n>2)
-		strncat (tmp, ",", 1);
-/*		snprintf (tmp, 16, "%.6f,", length);
-	else
-		snprintf (tmp, 16, "%.6f", length);
-*/
-	if (strlen (StrTree) + strlen (tmp) < MAX_INPUT_SIZE-1)
-	{
-		strncat (StrTree, ":", 1);
-		strncat (StrTree, tmp, strlen (tmp));
-	}
+		length[2] = Finish_branch_length_MVR (last[2], last[1], last[0], delta, n);
 
-	if (n>2) 
-	{
-		length = Finish_branch_length_MVR (last[2], last[1], last[0], delta, n);
-		Print_outputChar (last[2], trees, StrTree);
-		//snprintf (tmp, 16, "%.6f", length);
-		snprintf (tmp, INPUT_SIZE, format, length);
-		if (strlen (StrTree) + strlen (tmp) < MAX_INPUT_SIZE-1)
-		{
-			strncat (StrTree, ":", 1);
-			strncat (StrTree, tmp, strlen (tmp));
-			//strncat (StrTree, ",", 1);
-		}
-	}
-
-	if (strlen (StrTree) < MAX_INPUT_SIZE-3)
-		strncat (StrTree, ");\n", 3);
-
-	if (n>3)
-		n=3;
-		
-	for(i=0; i<n; i++)
-	{
-		bidon = trees[last[i]].head;
-		ele = bidon;
-		while (bidon != NULL)
-		{
-			ele = ele->suiv;
-			free (bidon);
-			bidon = ele;
-		}
-	}
-
-	free (tmp);
-
-	return;
+	return (Print_output (trees, last, length, n>3 ? 3 : n, format));
 }
 
 /*;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;*\
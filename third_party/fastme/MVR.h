@@ -27,7 +27,7 @@
 tree *unj (double **D, set *species, int n, const char *format);
 int SymmetrizeMVR (double **delta, int n);
 double Finish_branch_length_MVR (int i, int j, int k, double **delta, int n);
-void FinishStrMVR (double **delta, int n, POINTERS *trees, char *StrTree, const char *format);
+char *FinishStrMVR (double **delta, int n, POINTERS *trees, const char *format);
 void Branch_lengthMVR (int a, int b, double *la, double *lb, double **delta, int n);
 double Reduction10MVR (int a, double la, int b, double lb, int i, double lamda, double **delta);
 double Reduction11MVR (int x, int y, int i, double **delta);
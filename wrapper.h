@@ -1 +1,2 @@
 #include "third_party/fastme/fastme.h"
+#include "rapidnj_wrapper.h"